@@ -0,0 +1,231 @@
+//! Outputs covering the whole collection rather than single songs.
+
+use unicode_normalization::UnicodeNormalization;
+use unicode_normalization::char::is_combining_mark;
+
+use print::{IndexEntry, IndexSection};
+use RefBook;
+use Role;
+use Song;
+
+/// What the collection-wide outputs need to know about a song.  Unlike a
+/// `Song`, this is plain data, so it can be sent back from worker threads.
+#[derive(Clone, Debug)]
+pub struct SongInfo {
+    pub title: String,
+    pub file_as: String,
+    pub cross_refs: Vec<CrossRefInfo>,
+    pub credits: Vec<CreditInfo>,
+}
+
+/// A credit naming a person; traditional credits aren't indexed.
+#[derive(Clone, Debug)]
+pub struct CreditInfo {
+    pub role: Role,
+    pub name: String,
+    pub sort_key: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct CrossRefInfo {
+    pub book: &'static RefBook,
+    pub number: Option<String>,
+    /// The song's title in the other book, if it differs from ours.
+    pub title: Option<String>,
+    /// Anything else to know about the other book's version.
+    pub note: Option<String>,
+}
+
+impl SongInfo {
+    pub fn new(song: &Song) -> SongInfo {
+        SongInfo {
+            title: song.plain_title().expect("Song requires a title"),
+            file_as: song.file_as().expect("Song requires a title"),
+            cross_refs: song.cross_refs().into_iter().map(|r| CrossRefInfo {
+                book: r.book,
+                number: r.number.clone(),
+                title: r.title.as_ref().map(|t| t.text.clone()),
+                note: r.note.as_ref().map(|t| t.text.clone()),
+            }).collect(),
+            credits: song.credits().into_iter().filter_map(|c| {
+                let person = c.person.as_ref()?;
+                Some(CreditInfo {
+                    role: c.role,
+                    name: person.name.clone(),
+                    sort_key: person.sort_key.clone(),
+                })
+            }).collect(),
+        }
+    }
+}
+
+/// A song's place in the book.
+#[derive(Debug)]
+pub struct Page {
+    pub info: SongInfo,
+    /// The page the song starts on.
+    pub page: u32,
+}
+
+/// Put the songs in filing order and number their pages.  Each song comes
+/// with the number of pages it takes.
+pub fn paginate(mut songs: Vec<(SongInfo, u32)>) -> Vec<Page> {
+    songs.sort_by_cached_key(|song| {
+        (collation_key(&song.0.file_as), song.0.file_as.clone())
+    });
+    let mut page = 1;
+    songs.into_iter().map(|(info, pages)| {
+        let placed = Page { info, page };
+        page += pages;
+        placed
+    }).collect()
+}
+
+/// One line of the concordance: "White Book #123 → our page 45".
+#[derive(Debug)]
+pub struct ConcordanceRow<'a> {
+    pub book: &'static RefBook,
+    pub number: &'a str,
+    pub their_title: Option<&'a str>,
+    pub note: Option<&'a str>,
+    pub our_title: &'a str,
+    pub our_page: u32,
+}
+
+/// Every numbered cross-reference in the book, sorted by book and then by
+/// number within it.
+pub fn concordance<'a>(pages: &'a [Page]) -> Vec<ConcordanceRow<'a>> {
+    let mut rows: Vec<ConcordanceRow> = pages.iter().flat_map(|page| {
+        page.info.cross_refs.iter().filter_map(move |r| {
+            Some(ConcordanceRow {
+                book: r.book,
+                number: r.number.as_ref()?,
+                their_title: r.title.as_deref(),
+                note: r.note.as_deref(),
+                our_title: &page.info.title,
+                our_page: page.page,
+            })
+        })
+    }).collect();
+    rows.sort_by(|a, b| {
+        (a.book.id, number_key(a.number), a.our_page)
+            .cmp(&(b.book.id, number_key(b.number), b.our_page))
+    });
+    rows
+}
+
+/// Sort "9" before "10", and "12" before "12a".
+fn number_key(number: &str) -> (u64, &str) {
+    let digits = number.find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(number.len());
+    let value = number[..digits].parse().unwrap_or(u64::MAX);
+    (value, &number[digits..])
+}
+
+/// Lay out the concordance as an index, with a section for each book.
+pub fn concordance_index(rows: &[ConcordanceRow]) -> Vec<IndexSection> {
+    let mut sections: Vec<IndexSection> = Vec::new();
+    for row in rows {
+        let new_book = match sections.last() {
+            Some(section) => section.heading != row.book.name,
+            None => true,
+        };
+        if new_book {
+            sections.push(IndexSection {
+                heading: row.book.name.to_string(),
+                entries: Vec::new(),
+            });
+        }
+        let mut text = match row.their_title {
+            Some(theirs) => format!("{} ({})", row.our_title, theirs),
+            None => row.our_title.to_string(),
+        };
+        if let Some(note) = row.note {
+            text.push_str(&format!(" — {}", note));
+        }
+        sections.last_mut().unwrap().entries.push(IndexEntry {
+            key: format!("#{}", row.number),
+            text,
+            page: row.our_page,
+        });
+    }
+    sections
+}
+
+/// The author index: everyone credited, alphabetized, with their songs.
+pub fn author_index(pages: &[Page]) -> Vec<IndexSection> {
+    let mut credits: Vec<(&CreditInfo, &Page)> = pages.iter()
+        .flat_map(|page| page.info.credits.iter().map(move |c| (c, page)))
+        .collect();
+    credits.sort_by_cached_key(|&(credit, page)| {
+        (collation_key(&credit.sort_key), credit.sort_key.clone(),
+         credit.name.clone(), page.page)
+    });
+
+    let mut sections: Vec<IndexSection> = Vec::new();
+    for (credit, page) in credits {
+        let new_person = match sections.last() {
+            Some(section) => section.heading != credit.sort_key,
+            None => true,
+        };
+        if new_person {
+            sections.push(IndexSection {
+                heading: credit.sort_key.clone(),
+                entries: Vec::new(),
+            });
+        }
+        sections.last_mut().unwrap().entries.push(IndexEntry {
+            key: credit.role.to_string(),
+            text: page.info.title.clone(),
+            page: page.page,
+        });
+    }
+    sections
+}
+
+/// A key that sorts text the way readers expect an index to: ignoring case,
+/// accents and punctuation, with ligatures spelt out, word by word, and with
+/// "Mc" filed as "Mac".
+pub fn collation_key(s: &str) -> String {
+    let mut key = String::with_capacity(s.len());
+    for c in s.nfkd() {
+        match c {
+            'æ' | 'Æ' => key.push_str("ae"),
+            'œ' | 'Œ' => key.push_str("oe"),
+            'ß' => key.push_str("ss"),
+            'ø' | 'Ø' => key.push('o'),
+            'ł' | 'Ł' => key.push('l'),
+            'đ' | 'Đ' | 'ð' | 'Ð' => key.push('d'),
+            'þ' | 'Þ' => key.push_str("th"),
+            c if is_combining_mark(c) => {},
+            c if c.is_alphanumeric() => key.extend(c.to_lowercase()),
+            // Word by word: "New York" before "Newark".  A comma ends a word
+            // too, as in "Lord's My Shepherd, The".
+            c if (c.is_whitespace() || c == ',')
+                && !key.is_empty() && !key.ends_with(' ') => key.push(' '),
+            _ => {},
+        }
+    }
+    let words: Vec<String> = key.split_whitespace().map(|word| {
+        match word.strip_prefix("mc") {
+            Some(rest) => format!("mac{}", rest),
+            None => word.to_string(),
+        }
+    }).collect();
+    words.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collation_keys() {
+        assert_eq!(collation_key("Ævum Æternum"), "aevum aeternum");
+        assert_eq!(collation_key("Élan, L’"), "elan l");
+        assert_eq!(collation_key("McDonald"), collation_key("MacDonald"));
+        assert_eq!(collation_key("Straße"), "strasse");
+        assert!(collation_key("New York") < collation_key("Newark"));
+        assert!(collation_key("Été") < collation_key("Exile"));
+    }
+}
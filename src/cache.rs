@@ -0,0 +1,137 @@
+//! The build cache: a manifest in the output directory recording what each
+//! source file was last rendered to, so unchanged songs can be skipped.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use print;
+
+const MANIFEST_NAME: &str = "manifest.tsv";
+const MANIFEST_HEADER: &str = "# songset manifest v1";
+
+/// What we know about one song's rendered output.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    /// Cache key of the inputs that produced this output; see `key`.
+    pub key: u64,
+    pub out_path: PathBuf,
+    pub pages: u32,
+    /// The font size the fitter settled on, in points.
+    pub font_size: f64,
+}
+
+/// The manifest, keyed by source file name.
+pub struct Manifest {
+    path: PathBuf,
+    entries: BTreeMap<String, Entry>,
+}
+
+impl Manifest {
+    /// Load the manifest from `out_dir`.  A missing or unreadable manifest
+    /// just means everything gets rebuilt, so that isn't an error.
+    pub fn load(out_dir: &Path) -> Manifest {
+        let path = out_dir.join(MANIFEST_NAME);
+        let entries = match fs::File::open(&path) {
+            Ok(file) => parse_manifest(BufReader::new(file)),
+            Err(_) => BTreeMap::new(),
+        };
+        Manifest { path, entries }
+    }
+
+    /// The entry for `name`, if it was built from inputs matching `key` and
+    /// its output still exists.
+    pub fn fresh(&self, name: &str, key: u64) -> Option<&Entry> {
+        self.entries.get(name)
+            .filter(|e| e.key == key && e.out_path.is_file())
+    }
+
+    pub fn insert(&mut self, name: String, entry: Entry) {
+        self.entries.insert(name, entry);
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.entries.remove(name);
+    }
+
+    /// Forget songs whose source file has gone away.
+    pub fn retain_names<F: Fn(&str) -> bool>(&mut self, keep: F) {
+        let gone: Vec<String> = self.entries.keys()
+            .filter(|name| !keep(name))
+            .cloned()
+            .collect();
+        for name in gone {
+            self.entries.remove(&name);
+        }
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let mut file = fs::File::create(&self.path)?;
+        writeln!(file, "{}", MANIFEST_HEADER)?;
+        for (name, e) in &self.entries {
+            writeln!(file, "{}\t{:016x}\t{}\t{}\t{}",
+                     name, e.key, e.out_path.display(), e.pages, e.font_size)?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_manifest<R: BufRead>(reader: R) -> BTreeMap<String, Entry> {
+    let mut lines = reader.lines();
+    match lines.next() {
+        Some(Ok(ref header)) if header == MANIFEST_HEADER => {},
+        // Some other version: start over rather than guess.
+        _ => return BTreeMap::new(),
+    }
+    lines.filter_map(|line| parse_entry(&line.ok()?)).collect()
+}
+
+fn parse_entry(line: &str) -> Option<(String, Entry)> {
+    let mut fields = line.split('\t');
+    let name = fields.next()?.to_string();
+    let key = u64::from_str_radix(fields.next()?, 16).ok()?;
+    let out_path = PathBuf::from(fields.next()?);
+    let pages = fields.next()?.parse().ok()?;
+    let font_size = fields.next()?.parse().ok()?;
+    if fields.next().is_some() {
+        return None;
+    }
+    Some((name, Entry { key, out_path, pages, font_size }))
+}
+
+/// The cache key for a song: covers the source text, the style settings and
+/// the renderer version, so a change to any of them forces a rebuild.
+pub fn key(source: &str, options: &print::Options) -> u64 {
+    let mut hash = Fnv::new();
+    hash.write(env!("CARGO_PKG_VERSION").as_bytes());
+    hash.write(&print::RENDERER_VERSION.to_le_bytes());
+    hash.write(print::style_fingerprint(options).as_bytes());
+    hash.write(source.as_bytes());
+    hash.finish()
+}
+
+/// FNV-1a.  Unlike std's `DefaultHasher`, its output is guaranteed not to
+/// change between Rust releases, which matters for keys stored on disk.
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Fnv {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= u64::from(*b);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+        // Separate fields so ("ab", "c") and ("a", "bc") differ.
+        self.0 ^= 0xff;
+        self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
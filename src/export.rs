@@ -0,0 +1,448 @@
+//! Song formats other than PDF.
+
+use std::cmp::Reverse;
+use std::ptr;
+
+use {FormattedText, LineKind, Song, Span, TextStyle, Verse};
+use {credit_line, rtl_language, sung_lines, verse_lines};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    Html,
+    /// Plain text for projection: one slide per verse, in performance order.
+    Slides,
+}
+
+impl Format {
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            Format::Json => "json",
+            Format::Html => "html",
+            Format::Slides => "slides.txt",
+        }
+    }
+}
+
+pub fn export(song: &Song, format: Format) -> String {
+    match format {
+        Format::Json => json_song(song),
+        Format::Html => html_song(song),
+        Format::Slides => slides_song(song),
+    }
+}
+
+fn json_song(song: &Song) -> String {
+    let text = |t: &FormattedText| json_string(&t.text);
+    let fields = vec![
+        ("title", json_option(song.title().map(text))),
+        ("alt_titles", json_array(song.alt_titles().into_iter().map(text))),
+        ("language", json_option(song.lang().map(json_string))),
+        ("attributions", json_array(song.attributions().into_iter().map(text))),
+        ("credits", json_array(song.credits().into_iter().map(|c| {
+            json_object(&[
+                ("role", json_string(&format!("{:?}", c.role).to_lowercase())),
+                ("name", json_option(c.person.as_ref()
+                                     .map(|p| json_string(&p.name)))),
+                ("sort_key", json_option(c.person.as_ref()
+                                         .map(|p| json_string(&p.sort_key)))),
+                ("year", json_option(c.year.as_ref().map(|y| json_string(y)))),
+                ("qualifier", json_option(c.qualifier.as_ref()
+                                          .map(|q| json_string(q)))),
+            ])
+        }))),
+        ("cross_refs", json_array(song.cross_refs().into_iter().map(|r| {
+            json_object(&[
+                ("book", json_string(r.book.id)),
+                ("number", json_option(r.number.as_ref().map(|n| json_string(n)))),
+                ("title", json_option(r.title.as_ref().map(text))),
+                ("note", json_option(r.note.as_ref().map(text))),
+            ])
+        }).chain(song.free_refs().into_iter().map(|r| {
+            json_object(&[("text", text(r))])
+        })))),
+        ("copyright", json_option(song.copyright().map(text))),
+        ("license", json_option(song.license().map(json_string))),
+        ("ccli", json_option(song.ccli().map(json_string))),
+        ("descant", song.has_descant().to_string()),
+        ("parallel", song.parallel().to_string()),
+        ("notes", json_array(song.notes().into_iter().map(|(kind, note)| {
+            json_object(&[
+                ("kind", json_string(&kind.to_lowercase())),
+                ("text", text(note)),
+            ])
+        }))),
+        ("verses", json_array(song.verses.iter().zip(song.verse_numbers())
+                              .map(|(verse, number)| json_verse(verse, number)))),
+        ("order", json_option(song.order().map(|steps| {
+            json_array(steps.iter().map(|step| json_string(step)))
+        }))),
+        // Indexes into "verses".
+        ("performance", json_array(song.performance().into_iter().map(|verse| {
+            song.verses.iter().position(|v| ptr::eq(v, verse)).unwrap().to_string()
+        }))),
+    ];
+    json_object(&fields) + "\n"
+}
+
+fn json_verse(verse: &Verse, number: Option<u32>) -> String {
+    let (kind, label, lines) = match *verse {
+        Verse::Normal(ref lines) => ("verse".to_string(), None, Some(lines)),
+        Verse::ChorusDef(ref label, ref lines) =>
+            ("chorus".to_string(), Some(label), Some(lines)),
+        Verse::RefrainDef(ref label, ref lines) =>
+            ("refrain".to_string(), Some(label), Some(lines)),
+        Verse::ChorusRef(ref label) => ("chorus-ref".to_string(), Some(label), None),
+        Verse::SectionBreak(ref label, _) =>
+            ("section-break".to_string(), Some(label), None),
+        Verse::SectionDef(section, ref label, ref lines) =>
+            (section.id().to_string(), Some(label), Some(lines)),
+        Verse::SectionRef(section, ref label) =>
+            (format!("{}-ref", section.id()), Some(label), None),
+    };
+    let mut fields = vec![("type", json_string(&kind))];
+    if let Some(number) = number {
+        fields.push(("number", number.to_string()));
+    }
+    if let Some(label) = label {
+        fields.push(("label", json_string(label)));
+    }
+    if let Verse::SectionBreak(_, Some(ref lang)) = *verse {
+        fields.push(("lang", json_string(lang)));
+    }
+    if let Some(lines) = lines {
+        fields.push(("lines", json_array(lines.iter().map(json_line))));
+    }
+    json_object(&fields)
+}
+
+fn json_line(line: &FormattedText) -> String {
+    let mut fields = vec![
+        ("indent", line.indent.to_string()),
+        ("text", json_string(&line.text)),
+    ];
+    if !line.spans.is_empty() {
+        fields.push(("spans", json_spans(line)));
+    }
+    if let Some(ref descant) = line.descant {
+        fields.push(("descant", json_string(&descant.text)));
+    }
+    if line.times > 1 {
+        fields.push(("times", line.times.to_string()));
+    }
+    if line.repeat_start {
+        fields.push(("repeat_start", "true".to_string()));
+    }
+    if let Some(times) = line.repeat_end {
+        fields.push(("repeat_end", times.to_string()));
+    }
+    match line.kind {
+        LineKind::Normal => {},
+        LineKind::InlineChorus => fields.push(("kind", json_string("chorus"))),
+        LineKind::ChorusMarker => fields.push(("kind", json_string("chorus-marker"))),
+    }
+    if !line.footnotes.is_empty() {
+        fields.push(("footnotes", json_array(line.footnotes.iter().map(|f| {
+            json_object(&[
+                ("marker", json_string(&f.marker)),
+                ("text", json_string(&f.text.text)),
+            ])
+        }))));
+    }
+    json_object(&fields)
+}
+
+/// The line's formatting, with offsets counted in characters.
+fn json_spans(line: &FormattedText) -> String {
+    let chars = |byte: usize| line.text[..byte].chars().count().to_string();
+    json_array(line.spans.iter().map(|span| {
+        let mut fields = vec![
+            ("style", json_string(span.style.id())),
+            ("start", chars(span.start)),
+            ("end", chars(span.end)),
+        ];
+        if let TextStyle::Language(ref lang) = span.style {
+            fields.push(("lang", json_string(lang)));
+        }
+        json_object(&fields)
+    }))
+}
+
+fn json_object(fields: &[(&str, String)]) -> String {
+    let fields: Vec<String> = fields.iter()
+        .map(|&(name, ref value)| format!("{}: {}", json_string(name), value))
+        .collect();
+    format!("{{{}}}", fields.join(", "))
+}
+
+fn json_array<I: Iterator<Item = String>>(items: I) -> String {
+    format!("[{}]", items.collect::<Vec<_>>().join(", "))
+}
+
+fn json_option(value: Option<String>) -> String {
+    value.unwrap_or_else(|| "null".to_string())
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn html_song(song: &Song) -> String {
+    let title = song.plain_title().unwrap_or_default();
+    let mut out = String::new();
+    out.push_str("<!DOCTYPE html>\n");
+    out.push_str(&format!("<html{}>\n", html_lang(Some(song.lang().unwrap_or("en")))));
+    out.push_str("<head>\n<meta charset=\"utf-8\">\n");
+    out.push_str(&format!("<title>{}</title>\n</head>\n", html_escape(&title)));
+    out.push_str("<body>\n<article class=\"song\">\n");
+
+    out.push_str(&format!("<h1>{}</h1>\n", song.title().map_or(String::new(), html_text)));
+    for alt_title in song.alt_titles() {
+        out.push_str(&format!("<p class=\"alt-title\">{}</p>\n", html_text(alt_title)));
+    }
+    for attrib in song.attributions() {
+        out.push_str(&format!("<p class=\"attrib\">{}</p>\n", html_text(attrib)));
+    }
+    if song.has_descant() {
+        out.push_str("<p class=\"badge\">descant</p>\n");
+    }
+    if let Some(credits) = credit_line(&song.credits()) {
+        out.push_str(&format!("<p class=\"credits\">{}</p>\n",
+                              html_text(&credits)));
+    }
+
+    let refs: Vec<String> = song.cross_refs().into_iter()
+        .map(|r| html_escape(&r.to_string()))
+        .chain(song.free_refs().into_iter().map(html_text))
+        .collect();
+    if !refs.is_empty() {
+        out.push_str(&format!("<p class=\"refs\">{}</p>\n", refs.join("; ")));
+    }
+
+    if let Some(steps) = song.order() {
+        out.push_str(&format!("<p class=\"order\">Order: {}</p>\n",
+                              html_escape(&steps.join(" "))));
+    }
+    let split = song.parallel_split();
+    let langs = song.verse_langs();
+    if split.is_some() {
+        out.push_str("<div class=\"parallel\">\n<div class=\"column\">\n");
+    }
+    // Outside parallel columns, what follows a section break in another
+    // language gets a div of its own.
+    let mut in_lang_div = false;
+    for (i, (verse, number)) in song.verses.iter().zip(song.verse_numbers()).enumerate() {
+        if Some(i) == split {
+            out.push_str(&format!("</div>\n<div class=\"column\"{}>\n",
+                                  html_lang(langs[i])));
+        } else if let Verse::SectionBreak(_, ref lang) = *verse {
+            if in_lang_div {
+                out.push_str("</div>\n");
+            }
+            in_lang_div = lang.is_some() && split.is_none();
+            if in_lang_div {
+                out.push_str(&format!("<div{}>\n", html_lang(lang.as_ref())));
+            }
+        }
+        out.push_str(&html_verse(verse, number));
+    }
+    if in_lang_div {
+        out.push_str("</div>\n");
+    }
+    if split.is_some() {
+        out.push_str("</div>\n</div>\n");
+    }
+
+    let footnotes = song.footnotes();
+    if !footnotes.is_empty() {
+        out.push_str("<ol class=\"footnotes\">\n");
+        for footnote in footnotes {
+            out.push_str(&format!("<li><span class=\"marker\">{}</span> {}</li>\n",
+                                  html_escape(&footnote.marker),
+                                  html_text(&footnote.text)));
+        }
+        out.push_str("</ol>\n");
+    }
+
+    let notes = song.notes();
+    if !notes.is_empty() {
+        out.push_str("<aside class=\"notes\">\n<dl>\n");
+        for (kind, note) in notes {
+            out.push_str(&format!("<dt>{}</dt><dd>{}</dd>\n",
+                                  html_escape(kind), html_text(note)));
+        }
+        out.push_str("</dl>\n</aside>\n");
+    }
+
+    if let Some(copyright) = song.copyright() {
+        out.push_str(&format!("<footer><p class=\"copyright\">{}</p></footer>\n",
+                              html_text(copyright)));
+    }
+    out.push_str("</article>\n</body>\n</html>\n");
+    out
+}
+
+fn html_verse(verse: &Verse, number: Option<u32>) -> String {
+    match *verse {
+        Verse::Normal(ref lines) => {
+            let number = number.map_or(String::new(), |n| {
+                format!("<span class=\"verse-number\">{}.</span>\n", n)
+            });
+            format!("<section class=\"verse\">\n{}{}</section>\n",
+                    number, html_lines(lines))
+        },
+        Verse::ChorusDef(ref label, ref lines) => {
+            format!("<section class=\"chorus\">\n<h2>{}:</h2>\n{}</section>\n",
+                    html_escape(label), html_lines(lines))
+        },
+        Verse::RefrainDef(ref label, ref lines) => {
+            format!("<section class=\"refrain\">\n<h2>{}:</h2>\n{}</section>\n",
+                    html_escape(label), html_lines(lines))
+        },
+        Verse::ChorusRef(ref label) => {
+            format!("<p class=\"chorus-ref\">{}</p>\n", html_escape(label))
+        },
+        Verse::SectionBreak(ref label, ref lang) => {
+            format!("<p class=\"section-break\"{}>{}</p>\n",
+                    html_lang(lang.as_ref()), html_escape(label))
+        },
+        Verse::SectionDef(section, ref label, ref lines) => {
+            format!("<section class=\"{}\">\n<h2>{}</h2>\n{}</section>\n",
+                    section.id(), html_escape(label), html_lines(lines))
+        },
+        Verse::SectionRef(section, ref label) => {
+            format!("<p class=\"section-ref {}\">{}</p>\n",
+                    section.id(), html_escape(label))
+        },
+    }
+}
+
+fn html_lines(lines: &[FormattedText]) -> String {
+    let lines: Vec<String> = lines.iter().map(|line| {
+        let kind = match line.kind {
+            LineKind::Normal => "",
+            LineKind::InlineChorus => " inline-chorus",
+            LineKind::ChorusMarker => " chorus-marker",
+        };
+        let mut text = html_text(line);
+        if line.repeat_start {
+            text.insert_str(0, "<span class=\"repeat-start\">‖:</span> ");
+        }
+        if let Some(ref descant) = line.descant {
+            text.push_str(&format!(" <span class=\"descant\">[{}]</span>",
+                                   html_text(descant)));
+        }
+        if let Some(times) = line.repeat_end {
+            text.push_str(" <span class=\"repeat-end\">:‖</span>");
+            if times > 2 {
+                text.push_str(&format!(" <span class=\"times\">×{}</span>", times));
+            }
+        }
+        if line.times > 1 {
+            text.push_str(&format!(" <span class=\"times\">×{}</span>", line.times));
+        }
+        format!("<span class=\"line indent-{}{}\">{}</span>", line.indent, kind, text)
+    }).collect();
+    format!("<p>\n{}\n</p>\n", lines.join("<br>\n"))
+}
+
+/// The title on its own slide, then each verse as performed.  Slides are
+/// separated by a line of "---".
+fn slides_song(song: &Song) -> String {
+    let mut slides = vec![song.plain_title().unwrap_or_default()];
+    for verse in song.performance() {
+        let lines = match verse_lines(verse) {
+            Some(lines) => lines,
+            None => continue,
+        };
+        let text: Vec<&str> = sung_lines(lines).into_iter()
+            .map(|line| line.text.trim())
+            .collect();
+        slides.push(text.join("\n"));
+    }
+    slides.join("\n---\n") + "\n"
+}
+
+/// Formatted text as HTML, with its spans as nested elements.
+fn html_text(ft: &FormattedText) -> String {
+    let mut spans: Vec<&Span> = ft.spans.iter().filter(|s| s.start < s.end).collect();
+    // Outer spans first, so they're opened first.
+    spans.sort_by_key(|s| (s.start, Reverse(s.end)));
+    let mut spans = spans.into_iter().peekable();
+
+    let mut out = String::new();
+    let mut open: Vec<&Span> = Vec::new();
+    let mut pos = 0;
+    loop {
+        while open.last().is_some_and(|s| s.end <= pos) {
+            out.push_str(html_tags(&open.pop().unwrap().style).1);
+        }
+        while spans.peek().is_some_and(|s| s.start == pos) {
+            let span = spans.next().unwrap();
+            out.push_str(&html_tags(&span.style).0);
+            open.push(span);
+        }
+        if pos == ft.text.len() {
+            break;
+        }
+        let next = open.last().map(|s| s.end).into_iter()
+            .chain(spans.peek().map(|s| s.start))
+            .chain(Some(ft.text.len()))
+            .min().unwrap();
+        out.push_str(&html_escape(&ft.text[pos..next]));
+        pos = next;
+    }
+    out
+}
+
+fn html_tags(style: &TextStyle) -> (String, &'static str) {
+    let (open, close) = match *style {
+        TextStyle::Language(ref lang) => {
+            return (format!("<span lang=\"{}\">", html_escape(lang)), "</span>");
+        },
+        TextStyle::Italic => ("<i>", "</i>"),
+        TextStyle::Bold => ("<b>", "</b>"),
+        TextStyle::SmallCaps => ("<span class=\"smallcaps\">", "</span>"),
+        TextStyle::Underline => ("<u>", "</u>"),
+        TextStyle::Superscript => ("<sup>", "</sup>"),
+        TextStyle::Subscript => ("<sub>", "</sub>"),
+        TextStyle::Strikethrough => ("<s>", "</s>"),
+    };
+    (open.to_string(), close)
+}
+
+/// A lang attribute, with a leading space, or nothing.
+fn html_lang<S: AsRef<str>>(lang: Option<S>) -> String {
+    lang.map_or(String::new(), |l| {
+        let l = l.as_ref();
+        let dir = if rtl_language(l) { " dir=\"rtl\"" } else { "" };
+        format!(" lang=\"{}\"{}", html_escape(l), dir)
+    })
+}
+
+fn html_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
@@ -1,16 +1,25 @@
+use std::borrow::Cow;
 use std::fmt;
 use std::fs;
 use std::io;
 use std::io::Read;
-use std::path::Path;
-
-#[macro_use]
-extern crate lazy_static;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
 
+extern crate glib;
 extern crate pango;
+extern crate pango_sys;
+extern crate unicode_normalization;
 
+mod book;
+mod cache;
+mod export;
 mod print;
 
+// Generated from grammar.peg by an old peg, whose code lints don't fit.
+#[allow(clippy::all, deprecated)]
 mod parse {
     include!(concat!(env!("OUT_DIR"), "/grammar.rs"));
 }
@@ -21,31 +30,685 @@ fn main() {
     assert!(dir.is_dir());
     assert!(out_dir.is_dir());
 
-    for entry in fs::read_dir(dir).unwrap() {
-        let entry = entry.unwrap();
-        println!("*** {} ***", entry.file_name().to_string_lossy());
-        let path = entry.path();
-        match read_song(&path) {
-            Err(e) => println!("Error: {}", e),
-            Ok(song) => {
-                let out_path = out_dir.join(entry.file_name());
-                match print::pdf_song(&out_path, &song) {
-                    Err(_) => println!("Print error"),
-                    Ok(_) => {},
+    let mut sources: Vec<PathBuf> = fs::read_dir(dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| is_song_source(path))
+        .collect();
+    sources.sort();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("list") => list(&sources, &args[1..]),
+        Some("usage") => usage_report(dir, &args[1..]),
+        Some("todo") => todo_report(&sources),
+        Some("check") => check_sources(&sources),
+        Some("export") => export_all(&sources, out_dir, &args[1..]),
+        _ => build(&sources, out_dir, &args),
+    }
+}
+
+fn build(sources: &[PathBuf], out_dir: &Path, args: &[String]) {
+    let flag = |name: &str| args.iter().any(|arg| arg == name);
+    let options = print::Options {
+        fractional_sizes: flag("--fractional-sizes"),
+        draft: flag("--draft"),
+        allow_overflow: flag("--allow-overflow"),
+        width_strategy: if flag("--wrap-long-lines") {
+            print::WidthStrategy::Wrap
+        } else {
+            print::WidthStrategy::Shrink
+        },
+    };
+    let filter = if flag("--public-domain-only") {
+        Filter::PublicDomainOnly
+    } else {
+        Filter::All
+    };
+    // A public edition or a draft has the same file names as the full
+    // edition, so each goes in a directory of its own, with its own
+    // manifest.
+    let mut edition_dir = out_dir.to_path_buf();
+    if filter == Filter::PublicDomainOnly {
+        edition_dir.push("public");
+    }
+    if options.draft {
+        edition_dir.push("draft");
+    }
+    if let Err(e) = fs::create_dir_all(&edition_dir) {
+        println!("Error creating {}: {}", edition_dir.display(), e);
+        return;
+    }
+    let out_dir = edition_dir.as_path();
+    let mut manifest = cache::Manifest::load(out_dir);
+    let outcomes = build_all(sources, out_dir, &options, filter, &manifest);
+
+    for outcome in &outcomes {
+        match outcome.entry {
+            Some(ref entry) => manifest.insert(outcome.name.clone(), entry.clone()),
+            None => manifest.remove(&outcome.name),
+        }
+    }
+    manifest.retain_names(|name| outcomes.iter().any(|o| o.name == name));
+    if let Err(e) = manifest.save() {
+        println!("Error writing manifest: {}", e);
+    }
+
+    let songs = outcomes.into_iter().filter_map(|outcome| {
+        Some((outcome.info?, outcome.entry?.pages))
+    }).collect();
+    let pages = book::paginate(songs);
+    let concordance = book::concordance_index(&book::concordance(&pages));
+    if print::pdf_index(&out_dir.join("concordance.pdf"),
+                        "Concordance", &concordance).is_err() {
+        println!("Error printing the concordance");
+    }
+    if print::pdf_index(&out_dir.join("authors.pdf"),
+                        "Authors", &book::author_index(&pages)).is_err() {
+        println!("Error printing the author index");
+    }
+}
+
+/// `songset todo`: list every to-do in the collection.
+fn todo_report(sources: &[PathBuf]) {
+    for path in sources {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        match read_source(path).and_then(|c| parse_songs(&c)) {
+            Err(e) => println!("{}: {}", name, e),
+            Ok(songs) => for todo in songs.iter().flat_map(|song| song.todos()) {
+                println!("{}: {}", name, todo.text);
+            },
+        }
+    }
+}
+
+/// `songset check`: report what each source needed normalized before it
+/// could be parsed, and any errors.
+fn check_sources(sources: &[PathBuf]) {
+    for path in sources {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let contents = match read_source(path) {
+            Err(e) => {
+                println!("{}: {}", name, e);
+                continue;
+            },
+            Ok(contents) => contents,
+        };
+        let (_, normalized) = normalize_source(&contents);
+        if !normalized.is_empty() {
+            println!("{}: {}", name, normalized);
+        }
+        if let Err(e) = parse_songs(&contents) {
+            println!("{}: {}", name, e);
+        }
+    }
+}
+
+/// `songset export json|html|slides`: write each song in another format,
+/// next to the PDFs.
+fn export_all(sources: &[PathBuf], out_dir: &Path, args: &[String]) {
+    let format = match args.first().map(String::as_str) {
+        Some("json") => export::Format::Json,
+        Some("html") => export::Format::Html,
+        Some("slides") => export::Format::Slides,
+        _ => {
+            println!("Usage: songset export json|html|slides");
+            return;
+        },
+    };
+    for path in sources {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let result = read_source(path)
+            .and_then(|c| parse_songs(&c))
+            .and_then(|songs| {
+                let names = song_names(path, songs.len())?;
+                for (song, song_name) in songs.iter().zip(names) {
+                    let out_path = out_dir.join(song_name)
+                        .with_extension(format.extension());
+                    fs::write(out_path, export::export(song, format))?;
                 }
+                Ok(())
+            });
+        if let Err(e) = result {
+            println!("{}: {}", name, e);
+        }
+    }
+}
+
+/// `songset usage NAME...`: report the named songs' CCLI numbers, for
+/// licensing returns.  Public domain songs aren't listed.
+fn usage_report(dir: &Path, names: &[String]) {
+    println!("CCLI\tTitle\tCopyright");
+    for name in names {
+        let songs = match read_source(&dir.join(name)).and_then(|c| parse_songs(&c)) {
+            Err(e) => {
+                println!("{}: {}", name, e);
+                continue;
             },
+            Ok(songs) => songs,
+        };
+        for song in songs.iter().filter(|song| !song.is_public_domain()) {
+            usage_line(song);
         }
     }
 }
 
-fn read_song(filepath: &Path) -> Result<Song, Error> {
+fn usage_line(song: &Song) {
+    let title = song.plain_title().expect("Song requires a title");
+    let copyright = song.copyright().map_or("", |c| &c.text);
+    match song.ccli() {
+        Some(number) => println!("{}\t{}\t{}", number, title, copyright),
+        None => println!("-\t{}\t{}\t(Warning: no CCLI number)",
+                         title, copyright),
+    }
+}
+
+/// `songset list --author NAME`: list the songs crediting anyone whose name
+/// contains NAME.
+fn list(sources: &[PathBuf], args: &[String]) {
+    let author = match (args.first().map(String::as_str), args.get(1)) {
+        (Some("--author"), Some(name)) => name.to_lowercase(),
+        _ => {
+            println!("Usage: songset list --author NAME");
+            return;
+        },
+    };
+    for path in sources {
+        let songs = match read_source(path).and_then(|c| parse_songs(&c)) {
+            Err(e) => {
+                println!("{}: {}", path.display(), e);
+                continue;
+            },
+            Ok(songs) => songs,
+        };
+        for song in &songs {
+            list_credits(song, &author);
+        }
+    }
+}
+
+/// Print the song's credits naming `author`, which is lowercase.
+fn list_credits(song: &Song, author: &str) {
+    let title = song.plain_title().expect("Song requires a title");
+    for credit in song.credits() {
+        if let Some(ref person) = credit.person {
+            if person.name.to_lowercase().contains(author) {
+                println!("{} ({}: {})", title, credit.role, person.name);
+            }
+        }
+    }
+}
+
+/// The result of building one song, passed back from a worker thread.
+/// Songs themselves can't cross threads (pango objects aren't `Send`), so
+/// each worker reads, translates and renders its songs start to finish.
+struct Outcome {
+    name: String,
+    log: Vec<String>,
+    /// The manifest entry for this song, or None to drop it.
+    entry: Option<cache::Entry>,
+    /// Set if the song could be read and translated.
+    info: Option<book::SongInfo>,
+}
+
+impl Outcome {
+    fn new(name: String) -> Outcome {
+        Outcome { name, log: Vec::new(), entry: None, info: None }
+    }
+
+    fn print(&self) {
+        println!("*** {} ***", self.name);
+        for message in &self.log {
+            println!("{}", message);
+        }
+    }
+}
+
+/// Which songs to build.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Filter {
+    All,
+    /// For public editions: only songs marked ⟦license public-domain⟧.
+    PublicDomainOnly,
+}
+
+/// Build every song across a pool of worker threads.  Progress goes to
+/// stderr as songs finish; each song's report goes to stdout in source order.
+fn build_all(sources: &[PathBuf], out_dir: &Path, options: &print::Options,
+             filter: Filter, manifest: &cache::Manifest) -> Vec<Outcome> {
+    let workers = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(sources.len())
+        .max(1);
+    let next = AtomicUsize::new(0);
+    let (tx, rx) = mpsc::channel();
+    let mut outcomes: Vec<Option<Vec<Outcome>>> = sources.iter().map(|_| None).collect();
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            let tx = tx.clone();
+            let next = &next;
+            scope.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                let path = match sources.get(i) {
+                    Some(path) => path,
+                    None => break,
+                };
+                if tx.send((i, build_file(path, out_dir, options, filter, manifest))).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(tx);
+
+        let mut printed = 0;
+        for (done, (i, file_outcomes)) in rx.iter().enumerate() {
+            let name = sources[i].file_name().unwrap_or_default();
+            eprintln!("[{}/{}] {}", done + 1, sources.len(), name.to_string_lossy());
+            outcomes[i] = Some(file_outcomes);
+            while let Some(Some(file_outcomes)) = outcomes.get(printed) {
+                for outcome in file_outcomes {
+                    outcome.print();
+                }
+                printed += 1;
+            }
+        }
+    });
+
+    outcomes.into_iter()
+        .flat_map(|o| o.expect("A worker thread stopped early"))
+        .collect()
+}
+
+/// Build every song in a source file.
+fn build_file(path: &Path, out_dir: &Path, options: &print::Options,
+              filter: Filter, manifest: &cache::Manifest) -> Vec<Outcome> {
+    let file_name = path.file_name().expect("Source paths name files")
+        .to_string_lossy();
+    let contents = match read_source(path) {
+        Err(e) => {
+            let mut outcome = Outcome::new(file_name.into_owned());
+            outcome.log.push(format!("Error: {}", e));
+            return vec![outcome];
+        },
+        Ok(contents) => contents,
+    };
+    let sources = split_songs(&contents);
+    let names = match song_names(path, sources.len()) {
+        Err(e) => {
+            let mut outcome = Outcome::new(file_name.into_owned());
+            outcome.log.push(format!("Error: {}", e));
+            return vec![outcome];
+        },
+        Ok(names) => names,
+    };
+    sources.into_iter().zip(names).map(|((first_line, source), name)| {
+        build_song(name, source, first_line, out_dir, options, filter, manifest)
+    }).collect()
+}
+
+fn build_song(name: String, contents: &str, first_line: usize, out_dir: &Path,
+              options: &print::Options, filter: Filter,
+              manifest: &cache::Manifest) -> Outcome {
+    let mut outcome = Outcome::new(name);
+
+    // Even if the PDF is up to date, the collection-wide outputs need the
+    // translated song.
+    let song = match parse_song(contents, first_line) {
+        Err(e) => {
+            outcome.log.push(format!("Error: {}", e));
+            return outcome;
+        },
+        Ok(song) => song,
+    };
+    if filter == Filter::PublicDomainOnly && !song.is_public_domain() {
+        outcome.log.push("Skipped: not public domain".to_string());
+        return outcome;
+    }
+    outcome.info = Some(book::SongInfo::new(&song));
+
+    let key = cache::key(contents, options);
+    if let Some(entry) = manifest.fresh(&outcome.name, key) {
+        outcome.log.push("Up to date".to_string());
+        outcome.entry = Some(entry.clone());
+        return outcome;
+    }
+
+    let out_path = out_dir.join(&outcome.name);
+    match print::pdf_song(&out_path, &song, options) {
+        Err(_) => outcome.log.push(
+            "Print error: doesn't fit on the page (see --allow-overflow)".to_string()),
+        Ok(output) => {
+            let fit = output.fit;
+            outcome.log.push(format!("{}pt in {} column(s)",
+                                     fit.font_size, fit.columns));
+            if fit.wrapped_lines > 0 {
+                outcome.log.push(format!("{} line(s) wrapped",
+                                         fit.wrapped_lines));
+            }
+            if fit.overflow {
+                outcome.log.push(
+                    "Warning: doesn't fit on the page".to_string());
+            }
+            if fit.parallel_dropped {
+                outcome.log.push(
+                    "Warning: printed in one column, not in parallel".to_string());
+            }
+            outcome.entry = Some(cache::Entry {
+                key,
+                out_path,
+                pages: output.pages,
+                font_size: fit.font_size,
+            });
+        },
+    }
+    outcome
+}
+
+/// Read a source file, with its ⟦include⟧s filled in.
+fn read_source(filepath: &Path) -> Result<String, Error> {
+    read_including(filepath, &mut Vec::new())
+}
+
+/// `including` is the chain of files that led here, to catch a file that
+/// ends up including itself.
+fn read_including(filepath: &Path, including: &mut Vec<PathBuf>)
+-> Result<String, Error> {
+    let canonical = filepath.canonicalize()?;
+    if including.contains(&canonical) {
+        let chain: Vec<String> = including.iter()
+            .chain(Some(&canonical))
+            .map(|p| p.display().to_string())
+            .collect();
+        return Err(Error::new(format!("Include cycle: {}", chain.join(" → "))));
+    }
+
     let mut file = fs::File::open(filepath)?;
     let mut contents = String::new();
     file.read_to_string(&mut contents)?;
-    let parsed = parse::song(&contents)?;
+    if !contents.split('\n').any(|line| include_target(line).is_some()) {
+        return Ok(contents);
+    }
+
+    including.push(canonical);
+    let dir = filepath.parent().unwrap_or(Path::new(""));
+    let mut lines = Vec::new();
+    for line in contents.split('\n') {
+        match include_target(line) {
+            Some(target) => {
+                let included = read_including(&dir.join(target), including)
+                    .map_err(|e| Error::new(format!("In {}: {}", target, e)))?;
+                lines.push(included.trim_start_matches('\u{feff}')
+                           .trim_end_matches(['\n', '\r'])
+                           .to_string());
+            },
+            None => lines.push(line.to_string()),
+        }
+    }
+    including.pop();
+    Ok(lines.join("\n"))
+}
+
+/// Files holding only part of a song, such as a chorus shared by several,
+/// end in this.  They're read through ⟦include⟧, but not built as songs.
+const PART_EXTENSION: &str = "part";
+
+/// Whether a file in the source directory is a song to build, rather than
+/// a part for songs to include.
+fn is_song_source(path: &Path) -> bool {
+    path.extension().is_none_or(|ext| ext != PART_EXTENSION)
+}
+
+/// The file named by an ⟦include FILE⟧ line.  Including is done on the
+/// text, before parsing, so the included lines stand in for the ⟦include⟧
+/// line; give it a block of its own to include whole verses.  The file is
+/// found next to the one including it, and is usually a .part file, so
+/// that it isn't also built as a song of its own.
+fn include_target(line: &str) -> Option<&str> {
+    let line = line.trim();
+    let (inner, rest) = if let Some(line) = line.strip_prefix("⟦") {
+        let end = line.find('⟧')?;
+        (&line[..end], &line[end + '⟧'.len_utf8()..])
+    } else if let Some(line) = line.strip_prefix("[[") {
+        let end = line.find("]]")?;
+        (&line[..end], &line[end + 2..])
+    } else {
+        return None;
+    };
+    // Only a comment may follow: ⟦include chorus.part⟧ ⟦// shared⟧
+    let rest = rest.trim();
+    let comment = (rest.starts_with("⟦//") && rest.ends_with('⟧'))
+        || (rest.starts_with("[[//") && rest.ends_with("]]"));
+    if !rest.is_empty() && !comment {
+        return None;
+    }
+    let target = inner.strip_prefix("include ")?.trim();
+    if target.is_empty() { None } else { Some(target) }
+}
+
+/// A line of this between songs lets one file hold several.
+const SONG_SEPARATOR: &str = "===";
+
+/// Split a file holding several songs into the songs' sources, each with
+/// the number of lines in the file before it.  Nothing but whitespace
+/// between separators, as after a separator at the end, isn't a song.
+fn split_songs(contents: &str) -> Vec<(usize, &str)> {
+    let mut songs = Vec::new();
+    let mut start = 0;
+    let mut first_line = 0;
+    let mut at = 0;
+    for (n, line) in contents.split('\n').enumerate() {
+        let next = at + line.len() + 1;
+        if line.trim() == SONG_SEPARATOR {
+            songs.push((first_line, &contents[start..at]));
+            start = next.min(contents.len());
+            first_line = n + 1;
+        }
+        at = next;
+    }
+    songs.push((first_line, &contents[start..]));
+    songs.retain(|&(_, song)| !song.trim().is_empty());
+    if songs.is_empty() {
+        // Still parse it, to report it as empty.
+        songs.push((0, contents));
+    }
+    songs
+}
+
+/// The name for the nth song from a file, for its output and its manifest
+/// entry: the file's own name, unless the file holds several songs, when
+/// they're numbered "carols-1.song", "carols-2.song" and so on.
+fn song_name(file_name: &str, n: usize, count: usize) -> String {
+    if count == 1 {
+        return file_name.to_string();
+    }
+    let path = Path::new(file_name);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    match path.extension() {
+        Some(ext) => format!("{}-{}.{}", stem, n + 1, ext.to_string_lossy()),
+        None => format!("{}-{}", stem, n + 1),
+    }
+}
+
+/// The names for the songs from a source file.  A numbered name mustn't be
+/// the name of another file next to it, or their outputs would overwrite
+/// each other.
+fn song_names(path: &Path, count: usize) -> Result<Vec<String>, Error> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    (0..count).map(|n| {
+        let name = song_name(&file_name, n, count);
+        if count > 1 && path.with_file_name(&name).exists() {
+            return Err(Error::new(format!(
+                "Song {} would be named {}, like another source file", n + 1, name)));
+        }
+        Ok(name)
+    }).collect()
+}
+
+/// Parse every song in a file.
+fn parse_songs(contents: &str) -> Result<Vec<Song>, Error> {
+    let sources = split_songs(contents);
+    let count = sources.len();
+    sources.into_iter().enumerate().map(|(n, (first_line, source))| {
+        parse_song(source, first_line).map_err(|e| if count == 1 {
+            e
+        } else {
+            Error::new(format!("Song {}: {}", n + 1, e))
+        })
+    }).collect()
+}
+
+/// Parse a song's source, which starts after `first_line` lines of its
+/// file, so that errors give the line in the file.
+fn parse_song(contents: &str, first_line: usize) -> Result<Song, Error> {
+    let (contents, _) = normalize_source(contents);
+    check_tabs(&contents, first_line)?;
+    let parsed = parse::song(&contents).map_err(|mut e| {
+        e.line += first_line;
+        e
+    })?;
+    let parsed = drop_comments(parsed);
+    if parsed.is_empty() {
+        return Err(Error::new("Nothing but comments".to_string()));
+    }
     tr_song(&parsed)
 }
 
+/// What `normalize_source` had to change.
+#[derive(Debug, Default)]
+struct Normalized {
+    bom: bool,
+    crlf: usize,
+    blank_lines: usize,
+    trailing_whitespace: usize,
+}
+
+impl Normalized {
+    fn is_empty(&self) -> bool {
+        !self.bom && self.crlf == 0 && self.blank_lines == 0
+            && self.trailing_whitespace == 0
+    }
+}
+
+impl fmt::Display for Normalized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut changes = Vec::new();
+        if self.bom {
+            changes.push("removed byte order mark".to_string());
+        }
+        if self.crlf > 0 {
+            changes.push(format!("{} CRLF line ending(s)", self.crlf));
+        }
+        if self.blank_lines > 0 {
+            changes.push(format!("{} whitespace-only line(s)", self.blank_lines));
+        }
+        if self.trailing_whitespace > 0 {
+            changes.push(format!("{} line(s) with trailing whitespace",
+                                 self.trailing_whitespace));
+        }
+        write!(f, "Normalized {}", changes.join(", "))
+    }
+}
+
+/// Undo what editors on other systems do to a file: drop a byte order mark,
+/// turn CRLF into LF, and strip trailing spaces, so that a line of spaces
+/// and tabs is blank and separates verses as it looks like it should.  Other
+/// whitespace, like a no-break space, may be meant and is kept.
+fn normalize_source(contents: &str) -> (String, Normalized) {
+    let mut normalized = Normalized::default();
+    let contents = match contents.strip_prefix('\u{feff}') {
+        Some(rest) => {
+            normalized.bom = true;
+            rest
+        },
+        None => contents,
+    };
+    let lines: Vec<&str> = contents.split('\n').map(|line| {
+        let line = match line.strip_suffix('\r') {
+            Some(line) => {
+                normalized.crlf += 1;
+                line
+            },
+            None => line,
+        };
+        // A line of nothing but spaces and tabs is blank, but a tab after
+        // text is still an error.
+        let trimmed = if line.trim_start_matches([' ', '\t', '\r']).is_empty() {
+            ""
+        } else {
+            line.trim_end_matches([' ', '\r'])
+        };
+        if trimmed.len() < line.len() {
+            if trimmed.is_empty() {
+                normalized.blank_lines += 1;
+            } else {
+                normalized.trailing_whitespace += 1;
+            }
+        }
+        trimmed
+    }).collect();
+    (lines.join("\n"), normalized)
+}
+
+/// The grammar doesn't allow tabs, except in a comment line, and the
+/// parser's own error for one is just "expected plain text", so catch them
+/// first.
+fn check_tabs(contents: &str, first_line: usize) -> Result<(), Error> {
+    for (n, line) in contents.lines().enumerate() {
+        if line.trim_start_matches(' ').starts_with("//") {
+            continue;
+        }
+        if let Some(at) = line.find('\t') {
+            return Err(Error::new(format!(
+                "Tab at line {}, column {}; tabs are only allowed in // comment \
+                 lines, so please convert them to spaces (e.g. with `expand -t 4`)",
+                first_line + n + 1, line[..at].chars().count() + 1)));
+        }
+    }
+    Ok(())
+}
+
+/// Drop ⟦// …⟧ comment tags, and then the lines holding nothing but a
+/// comment, which would otherwise be empty lines in the middle of a verse;
+/// a block of them is no verse at all.
+fn drop_comments(song: Vec<Vec<Line>>) -> Vec<Vec<Line>> {
+    song.into_iter()
+        .map(|block| block.into_iter().filter_map(|mut line| {
+            line.items = drop_comment_tags(line.items);
+            if line.items.is_empty() { None } else { Some(line) }
+        }).collect())
+        .filter(|block: &Vec<Line>| !block.is_empty())
+        .collect()
+}
+
+/// Drop ⟦// …⟧ tags, at any depth, along with the space they leave behind:
+/// "A ⟦// note⟧ B" becomes "A B".
+fn drop_comment_tags(items: Vec<Item>) -> Vec<Item> {
+    let mut kept: Vec<Item> = Vec::new();
+    for item in items {
+        match item {
+            Item::Sexp(Sexp { keyword: "//", .. }) => {
+                if let Some(&mut Item::Text(ref mut s)) = kept.last_mut() {
+                    *s = s.trim_end_matches(' ');
+                }
+            },
+            Item::Sexp(sexp) => {
+                let items = drop_comment_tags(sexp.items);
+                kept.push(Item::Sexp(Sexp { keyword: sexp.keyword, items }));
+            },
+            Item::Text(s) if kept.is_empty() && s.starts_with(' ') => {
+                kept.push(Item::Text(s.trim_start_matches(' ')));
+            },
+            item => kept.push(item),
+        }
+    }
+    kept.retain(|item| match *item {
+        Item::Text(s) => !s.is_empty(),
+        Item::Sexp(_) => true,
+    });
+    kept
+}
+
 // Structs produced by the parser:
 
 #[derive(Debug)]
@@ -54,7 +717,7 @@ pub struct Line<'a> {
     items: Vec<Item<'a>>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct Sexp<'a> {
     keyword: &'a str,
     items: Vec<Item<'a>>,
@@ -63,7 +726,7 @@ struct Sexp<'a> {
 impl<'a> fmt::Display for Sexp<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let items = self.items.iter().map(|item| match *item {
-            Item::Text(ref s) => format!("{}", s),
+            Item::Text(s) => s.to_string(),
             Item::Sexp(ref sexp) => format!("{}", sexp),
         }).collect::<Vec<String>>().concat();
         write!(f, "⟦{} {}⟧", self.keyword, items)
@@ -71,19 +734,23 @@ impl<'a> fmt::Display for Sexp<'a> {
 }
 
 impl<'a> Sexp<'a> {
-    fn opt_string_arg(&self) -> Result<Option<&'a str>, String> {
-        let item = match self.items.len() {
-            0 => return Ok(None),
-            1 => &self.items[0],
-            _ => return Err(format!("Too many arguments in {:?}", self)),
-        };
-        match *item {
-            Item::Text(s) => Ok(Some(s)),
-            _ => Err(format!("Expected string argument in {:?}", self)),
+    /// The text of the arguments.  An escape splits the text into several
+    /// items, which are joined up again here.
+    fn opt_string_arg(&self) -> Result<Option<Cow<'a, str>>, String> {
+        let mut text: Option<Cow<'a, str>> = None;
+        for item in &self.items {
+            match *item {
+                Item::Text(s) => match text {
+                    Some(ref mut t) => t.to_mut().push_str(s),
+                    None => text = Some(Cow::Borrowed(s)),
+                },
+                _ => return Err(format!("Expected string argument in {:?}", self)),
+            }
         }
+        Ok(text)
     }
 
-    fn string_arg(&self) -> Result<&'a str, String> {
+    fn string_arg(&self) -> Result<Cow<'a, str>, String> {
         self.opt_string_arg()?
             .ok_or(format!("An argument is required for {:?}", self))
     }
@@ -101,7 +768,7 @@ impl<'a> Sexp<'a> {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 enum Item<'a> {
     Text(&'a str),
     Sexp(Sexp<'a>),
@@ -116,7 +783,7 @@ pub struct Song {
 }
 
 impl Song {
-    fn title<'a>(&'a self) -> Option<&'a FormattedText> {
+    fn title(&self) -> Option<&FormattedText> {
         for m in self.meta.iter() {
             if let Metadata::Title(ref t) = *m {
                 return Some(t);
@@ -125,7 +792,93 @@ impl Song {
         None
     }
 
-    fn lang<'a>(&'a self) -> Option<&'a str> {
+    /// The title as plain text, without footnote markers.
+    fn plain_title(&self) -> Option<String> {
+        self.title().map(FormattedText::unmarked_text)
+    }
+
+    fn alt_titles(&self) -> Vec<&FormattedText> {
+        self.meta.iter().filter_map(|m| match *m {
+            Metadata::AltTitle(ref t) => Some(t),
+            _ => None,
+        }).collect()
+    }
+
+    fn attributions(&self) -> Vec<&FormattedText> {
+        self.meta.iter().filter_map(|m| match *m {
+            Metadata::Attrib(ref t) => Some(t),
+            _ => None,
+        }).collect()
+    }
+
+    fn copyright(&self) -> Option<&FormattedText> {
+        self.meta.iter().filter_map(|m| match *m {
+            Metadata::Copyright(ref c) => Some(c),
+            _ => None,
+        }).next()
+    }
+
+    fn license(&self) -> Option<&str> {
+        self.meta.iter().filter_map(|m| match *m {
+            Metadata::License(ref l) => Some(l.as_str()),
+            _ => None,
+        }).next()
+    }
+
+    fn ccli(&self) -> Option<&str> {
+        self.meta.iter().filter_map(|m| match *m {
+            Metadata::Ccli(ref n) => Some(n.as_str()),
+            _ => None,
+        }).next()
+    }
+
+    /// Only songs explicitly marked as such count: a missing ⟦copyright⟧
+    /// more likely means nobody has looked it up yet.
+    fn is_public_domain(&self) -> bool {
+        self.license() == Some("public-domain")
+    }
+
+    /// Editorial notes, with a label for each kind: origin, source, notes
+    /// and to-dos, in file order.
+    fn notes(&self) -> Vec<(&'static str, &FormattedText)> {
+        self.meta.iter().filter_map(|m| match *m {
+            Metadata::Origin(ref t) => Some(("Origin", t)),
+            Metadata::Source(ref t) => Some(("Source", t)),
+            Metadata::Note(ref t) => Some(("Note", t)),
+            Metadata::Todo(ref t) => Some(("TODO", t)),
+            _ => None,
+        }).collect()
+    }
+
+    fn todos(&self) -> Vec<&FormattedText> {
+        self.meta.iter().filter_map(|m| match *m {
+            Metadata::Todo(ref t) => Some(t),
+            _ => None,
+        }).collect()
+    }
+
+    fn credits(&self) -> Vec<&Credit> {
+        self.meta.iter().filter_map(|m| match *m {
+            Metadata::Credit(ref c) => Some(c),
+            _ => None,
+        }).collect()
+    }
+
+    fn cross_refs(&self) -> Vec<&CrossRef> {
+        self.meta.iter().filter_map(|m| match *m {
+            Metadata::CrossRef(ref r) => Some(r),
+            _ => None,
+        }).collect()
+    }
+
+    fn free_refs(&self) -> Vec<&FormattedText> {
+        self.meta.iter().filter_map(|m| match *m {
+            Metadata::FreeRef(ref t) => Some(t),
+            _ => None,
+        }).collect()
+    }
+
+    fn lang(&self) -> Option<&str> {
         for m in self.meta.iter() {
             if let Metadata::Language(ref lang) = *m {
                 return Some(lang);
@@ -134,39 +887,339 @@ impl Song {
         None
     }
 
-    fn file_as(&self) -> Option<String> {
-        let title = &self.title()?.text;
-        let lang = self.lang().unwrap_or("en");
-        let ignore = match lang {
-            "en" => vec!["the ", "a "],
-            "fr" => vec!["le ", "la ", "l’", "une ", "un "],
-            _ => vec![],
+    /// The ⟦order …⟧ the song is performed in, e.g. ["V1", "C", "V2", "C"].
+    fn order(&self) -> Option<&[String]> {
+        self.meta.iter().filter_map(|m| match *m {
+            Metadata::Order(ref steps) => Some(steps.as_slice()),
+            _ => None,
+        }).next()
+    }
+
+    /// The verse a step of the ⟦order⟧ stands for.  "V2" is the second normal
+    /// verse, "C" or "C1" the first chorus, "R" a refrain, and "I", "B", "T",
+    /// "Coda" and "O" the other sections, which may also be spelt out.
+    fn order_step(&self, step: &str) -> Option<usize> {
+        match self.order_step_matches(step).as_slice() {
+            &[i] => Some(i),
+            _ => None,
+        }
+    }
+
+    /// Every verse a step of the ⟦order⟧ could mean.  Normal verses are
+    /// counted the way `verse_numbers` counts them, so "V2" is the verse
+    /// printed as 2, which is ambiguous if numbering starts again after a
+    /// section break.
+    fn order_step_matches(&self, step: &str) -> Vec<usize> {
+        let digits = step.find(|c: char| c.is_ascii_digit()).unwrap_or(step.len());
+        let (kind, n) = step.split_at(digits);
+        let n: u32 = match n {
+            "" => 1,
+            n => match n.parse() {
+                Ok(n) => n,
+                Err(_) => return Vec::new(),
+            },
         };
-        let lc_title = title.to_lowercase();
-        for prefix in ignore {
-            if lc_title.starts_with(prefix) {
-                // XXX Unsafe if to_lowercase changes the length!
-                let (a, b) = title.split_at(prefix.len());
-                return Some(format!("{}, {}", b, a));
+        if kind == "V" {
+            return self.normal_verse_counts().into_iter().enumerate()
+                .filter(|&(_, count)| count == Some(n))
+                .map(|(i, _)| i)
+                .collect();
+        }
+        let is_kind = |verse: &Verse| match *verse {
+            Verse::ChorusDef(_, _) => kind == "C",
+            Verse::RefrainDef(_, _) => kind == "R",
+            Verse::SectionDef(section, _, _) =>
+                kind == section.abbrev() || kind == section.to_string(),
+            _ => false,
+        };
+        self.verses.iter().enumerate()
+            .filter(|&(_, verse)| is_kind(verse))
+            .map(|(i, _)| i)
+            .nth((n as usize).wrapping_sub(1))
+            .into_iter()
+            .collect()
+    }
+
+    /// The verses as they're performed: in ⟦order⟧ if there is one.  If not,
+    /// in file order, with each ⟦Chorus⟧ or ⟦Bridge⟧ replaced by the section
+    /// it refers to, and section breaks left out.
+    fn performance(&self) -> Vec<&Verse> {
+        if let Some(steps) = self.order() {
+            // tr_song checked that every step refers to a verse.
+            return steps.iter()
+                .filter_map(|step| self.order_step(step))
+                .map(|i| &self.verses[i])
+                .collect();
+        }
+        let mut chorus = self.verses.iter().find(|v| matches!(**v, Verse::ChorusDef(_, _)));
+        let mut performance = Vec::new();
+        for verse in &self.verses {
+            match *verse {
+                Verse::ChorusDef(_, _) => {
+                    chorus = Some(verse);
+                    performance.push(verse);
+                },
+                Verse::ChorusRef(_) => performance.extend(chorus),
+                Verse::SectionRef(section, ref label) => {
+                    performance.extend(self.verses.iter().find(|v| match **v {
+                        Verse::SectionDef(s, ref l, _) => s == section && l == label,
+                        _ => false,
+                    }));
+                },
+                Verse::SectionBreak(_, _) => {},
+                _ => performance.push(verse),
             }
         }
-        Some(title.to_string())
+        performance
+    }
+
+    /// Whether the sections either side of a section break are printed side
+    /// by side, as for a song given in two languages.
+    fn parallel(&self) -> bool {
+        self.meta.iter().any(|m| matches!(*m, Metadata::Parallel))
+    }
+
+    /// For a ⟦parallel⟧ song, the index of the section break between the
+    /// two columns.
+    fn parallel_split(&self) -> Option<usize> {
+        if !self.parallel() {
+            return None;
+        }
+        self.verses.iter().position(|verse| matches!(*verse, Verse::SectionBreak(_, _)))
+    }
+
+    /// The language of each verse: the song's, until a section break gives
+    /// another.
+    fn verse_langs(&self) -> Vec<Option<&str>> {
+        let mut lang = self.lang();
+        self.verses.iter().map(|verse| {
+            if let Verse::SectionBreak(_, Some(ref l)) = *verse {
+                lang = Some(l);
+            }
+            lang
+        }).collect()
+    }
+
+    fn numbered_verses(&self) -> bool {
+        self.meta.iter().any(|m| matches!(*m, Metadata::NumberedVerses))
+    }
+
+    /// Whether the song has a descant: either it's marked in the verses, or
+    /// the meta block says there is one somewhere.
+    fn has_descant(&self) -> bool {
+        let flagged = self.meta.iter().any(|m| matches!(*m, Metadata::Descant));
+        flagged || self.verses.iter()
+            .filter_map(verse_lines)
+            .flat_map(|lines| lines.iter())
+            .any(|line| line.descant.is_some())
+    }
+
+    fn inline_chorus(&self) -> bool {
+        self.meta.iter().any(|m| matches!(*m, Metadata::InlineChorus))
+    }
+
+    fn inline_chorus_markers(&self) -> bool {
+        self.meta.iter().any(|m| matches!(*m, Metadata::InlineChorusMarkers))
+    }
+
+    /// The number of each verse, if the song has ⟦numbered-verses⟧.
+    fn verse_numbers(&self) -> Vec<Option<u32>> {
+        if self.numbered_verses() {
+            self.normal_verse_counts()
+        } else {
+            vec![None; self.verses.len()]
+        }
+    }
+
+    /// Where each normal verse comes among them.  Counting starts again
+    /// after a section break, which often introduces a version in another
+    /// language.
+    fn normal_verse_counts(&self) -> Vec<Option<u32>> {
+        let mut count = 0;
+        self.verses.iter().map(|verse| match *verse {
+            Verse::Normal(_) => {
+                count += 1;
+                Some(count)
+            },
+            Verse::SectionBreak(_, _) => {
+                count = 0;
+                None
+            },
+            _ => None,
+        }).collect()
+    }
+
+    /// All the footnotes in the song, in order.
+    fn footnotes(&self) -> Vec<&Footnote> {
+        let title = self.title().into_iter();
+        let lines = self.verses.iter()
+            .filter_map(verse_lines)
+            .flat_map(|lines| lines.iter());
+        // A line's descant comes after it.
+        title.chain(lines)
+            .flat_map(|ft| Some(ft).into_iter().chain(ft.descant.as_deref()))
+            .flat_map(|ft| ft.footnotes.iter())
+            .collect()
+    }
+
+    /// The title as filed: from ⟦file-as⟧ if there is one, otherwise with a
+    /// leading article moved to the end, e.g. "Lord's My Shepherd, The".
+    fn file_as(&self) -> Option<String> {
+        let explicit = self.meta.iter().filter_map(|m| match *m {
+            Metadata::FileAs(ref s) => Some(s.clone()),
+            _ => None,
+        }).next();
+        if explicit.is_some() {
+            return explicit;
+        }
+        let title = self.plain_title()?;
+        match split_article(&title, self.lang().unwrap_or("en")) {
+            Some((article, rest)) => Some(format!("{}, {}", rest, article)),
+            None => Some(title),
+        }
     }
 }
 
+/// Leading articles to file titles without, by language.  Elided articles
+/// end with an apostrophe and need no space after them.
+const ARTICLES: &[(&str, &[&str])] = &[
+    ("en", &["the", "a", "an"]),
+    ("fr", &["le", "la", "les", "l’", "l'", "un", "une", "des"]),
+    ("de", &["der", "die", "das", "den", "dem", "des", "ein", "eine"]),
+    ("es", &["el", "la", "los", "las", "un", "una"]),
+    ("it", &["il", "lo", "la", "i", "gli", "le", "l’", "l'", "un", "uno", "una"]),
+    ("pt", &["o", "a", "os", "as", "um", "uma"]),
+    ("nl", &["de", "het", "een", "’t", "'t"]),
+    ("cy", &["y", "yr", "’r", "'r"]),
+    ("ga", &["an", "na"]),
+    ("gd", &["an", "am", "na", "nan"]),
+];
+
+/// Split a leading article off a title in the given language (an RFC5646
+/// tag; only the primary language matters), as ("The", "Lord's My
+/// Shepherd").  Case is ignored, comparing whole characters so that
+/// lowercasing can't shift the split.
+fn split_article<'a>(title: &'a str, lang: &str) -> Option<(&'a str, &'a str)> {
+    let primary = lang.split('-').next().unwrap_or(lang).to_lowercase();
+    let &(_, articles) = ARTICLES.iter().find(|&&(l, _)| l == primary)?;
+    for article in articles {
+        let len = article.chars().count();
+        let end = title.char_indices().nth(len).map_or(title.len(), |(i, _)| i);
+        let (head, rest) = title.split_at(end);
+        if head.to_lowercase() != *article {
+            continue;
+        }
+        let elided = article.ends_with('’') || article.ends_with('\'');
+        let rest = if elided {
+            rest
+        } else if rest.starts_with(' ') {
+            rest.trim_start()
+        } else {
+            continue;
+        };
+        if !rest.is_empty() {
+            return Some((head, rest));
+        }
+    }
+    None
+}
+
 #[derive(Debug)]
 enum Verse {
     Normal(Vec<FormattedText>),
     ChorusDef(String, Vec<FormattedText>),
     RefrainDef(String, Vec<FormattedText>),
     ChorusRef(String),
-    SectionBreak(String), // e.g. alt language or another poem to the same tune
+    /// E.g. alt language or another poem to the same tune: a label, and
+    /// the language of what follows, from a ⟦lang⟧ line after the break.
+    SectionBreak(String, Option<String>),
+    /// A labelled part of the song other than a verse or chorus, e.g.
+    /// ⟦Bridge:⟧.
+    SectionDef(Section, String, Vec<FormattedText>),
+    /// Where a section is sung again, e.g. ⟦Bridge⟧.
+    SectionRef(Section, String),
+}
+
+/// Kinds of labelled section, besides choruses and refrains.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Section {
+    Intro,
+    Bridge,
+    Tag,
+    Coda,
+    Outro,
+}
+
+const SECTIONS: [Section; 5] = [
+    Section::Intro, Section::Bridge, Section::Tag, Section::Coda, Section::Outro,
+];
+
+impl Section {
+    /// The name used in exported data, e.g. "bridge".
+    fn id(&self) -> &'static str {
+        match *self {
+            Section::Intro => "intro",
+            Section::Bridge => "bridge",
+            Section::Tag => "tag",
+            Section::Coda => "coda",
+            Section::Outro => "outro",
+        }
+    }
+
+    /// The short form used in ⟦order⟧, e.g. "B".
+    fn abbrev(&self) -> &'static str {
+        match *self {
+            Section::Intro => "I",
+            Section::Bridge => "B",
+            Section::Tag => "T",
+            // "C" is taken by the chorus.
+            Section::Coda => "Coda",
+            Section::Outro => "O",
+        }
+    }
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Section::Intro => "Intro",
+            Section::Bridge => "Bridge",
+            Section::Tag => "Tag",
+            Section::Coda => "Coda",
+            Section::Outro => "Outro",
+        })
+    }
+}
+
+/// The section a verse keyword introduces, and whether it defines the
+/// section ("Bridge:") rather than referring back to it ("Bridge").
+fn section_keyword(keyword: &str) -> Option<(Section, bool)> {
+    let (name, def) = match keyword.len().checked_sub(1) {
+        Some(n) if keyword.ends_with(':') => (&keyword[..n], true),
+        _ => (keyword, false),
+    };
+    SECTIONS.iter()
+        .find(|section| section.to_string() == name)
+        .map(|&section| (section, def))
 }
 
 struct FormattedText {
     text: String,
     formatting: pango::AttrList,
+    /// The same formatting as `formatting`, for outputs other than pango.
+    spans: Vec<Span>,
     indent: u32,
+    kind: LineKind,
+    footnotes: Vec<Footnote>,
+    /// Descant words sung over this line, marked ⟦descant …⟧.
+    descant: Option<Box<FormattedText>>,
+    /// How many times the line is sung, from ⟦×2⟧ at its end.
+    times: u32,
+    /// Whether a repeated passage starts with this line, at ⟦repeat⟧.
+    repeat_start: bool,
+    /// If a repeated passage ends with this line, how many times the passage
+    /// is sung.
+    repeat_end: Option<u32>,
 }
 impl fmt::Debug for FormattedText {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -179,11 +1232,195 @@ impl FormattedText {
         FormattedText {
             text: String::new(),
             formatting: pango::AttrList::new(),
+            spans: Vec::new(),
             indent: 0,
+            kind: LineKind::Normal,
+            footnotes: Vec::new(),
+            descant: None,
+            times: 1,
+            repeat_start: false,
+            repeat_end: None,
+        }
+    }
+
+    /// Add `other` to the end, keeping its formatting.
+    fn append(&mut self, other: &FormattedText) {
+        let at = self.text.len();
+        self.text.push_str(&other.text);
+        self.formatting.splice(&other.formatting, at as i32, other.text.len() as i32);
+        self.spans.extend(other.spans.iter().map(|span| Span {
+            style: span.style.clone(),
+            start: span.start + at,
+            end: span.end + at,
+        }));
+    }
+
+    /// The text without the markers numbering its footnotes, for use away
+    /// from the page the footnotes are printed on.
+    fn unmarked_text(&self) -> String {
+        let mut text = self.text.clone();
+        for footnote in self.footnotes.iter().rev() {
+            let end = footnote.anchor + footnote.marker.len();
+            text.replace_range(footnote.anchor..end, "");
+        }
+        text
+    }
+}
+
+/// A run of styled text, from ⟦bold …⟧ and the like.  Spans nest, since the
+/// sexps they come from do.
+#[derive(Clone, Debug, PartialEq)]
+struct Span {
+    style: TextStyle,
+    /// Byte offsets into the text.
+    start: usize,
+    end: usize,
+}
+
+impl Span {
+    /// Make room for `len` bytes inserted at `at`, the way pango's
+    /// `AttrList::splice` does: text inserted inside the span joins it.
+    fn insert(&mut self, at: usize, len: usize) {
+        if self.start > at {
+            self.start += len;
+        }
+        if self.end > at {
+            self.end += len;
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum TextStyle {
+    /// Text in another language than its surroundings, from ⟦lang fr …⟧:
+    /// an RFC5646 tag.
+    Language(String),
+    Italic,
+    Bold,
+    SmallCaps,
+    Underline,
+    Superscript,
+    Subscript,
+    Strikethrough,
+}
+
+impl TextStyle {
+    fn from_keyword(keyword: &str) -> Option<TextStyle> {
+        match keyword {
+            "italic" | "note" => Some(TextStyle::Italic),
+            "bold" => Some(TextStyle::Bold),
+            "smallcaps" => Some(TextStyle::SmallCaps),
+            "underline" => Some(TextStyle::Underline),
+            "sup" => Some(TextStyle::Superscript),
+            "sub" => Some(TextStyle::Subscript),
+            "strike" => Some(TextStyle::Strikethrough),
+            _ => None,
+        }
+    }
+
+    /// The name used in exported data.
+    fn id(&self) -> &'static str {
+        match *self {
+            TextStyle::Italic => "italic",
+            TextStyle::Bold => "bold",
+            TextStyle::Language(_) => "lang",
+            TextStyle::SmallCaps => "smallcaps",
+            TextStyle::Underline => "underline",
+            TextStyle::Superscript => "sup",
+            TextStyle::Subscript => "sub",
+            TextStyle::Strikethrough => "strike",
+        }
+    }
+
+    // When would the attribute constructors ever return None???
+    fn attributes(&self) -> Vec<pango::Attribute> {
+        match *self {
+            TextStyle::Language(ref lang) => vec![language_attribute(lang)],
+            TextStyle::Italic =>
+                vec![pango::Attribute::new_style(pango::Style::Italic).unwrap()],
+            TextStyle::Bold =>
+                vec![pango::Attribute::new_weight(pango::Weight::Bold).unwrap()],
+            TextStyle::SmallCaps =>
+                vec![pango::Attribute::new_variant(pango::Variant::SmallCaps).unwrap()],
+            TextStyle::Underline =>
+                vec![pango::Attribute::new_underline(pango::Underline::Single).unwrap()],
+            TextStyle::Superscript => vec![
+                pango::Attribute::new_rise(5 * pango::SCALE).unwrap(),
+                pango::Attribute::new_scale(0.7).unwrap(),
+            ],
+            TextStyle::Subscript => vec![
+                pango::Attribute::new_rise(-3 * pango::SCALE).unwrap(),
+                pango::Attribute::new_scale(0.7).unwrap(),
+            ],
+            TextStyle::Strikethrough =>
+                vec![pango::Attribute::new_strikethrough(true).unwrap()],
         }
     }
 }
 
+/// Languages written right to left, by their primary subtag.
+const RTL_LANGUAGES: &[&str] = &[
+    "ar", "arc", "ckb", "dv", "fa", "he", "ks", "ps", "sd", "syr", "ug",
+    "ur", "yi",
+];
+
+/// Whether a language tag such as "he" or "ar-EG" names a language written
+/// right to left.
+fn rtl_language(lang: &str) -> bool {
+    let primary = lang.split(['-', '_']).next().unwrap_or("");
+    RTL_LANGUAGES.iter().any(|rtl| rtl.eq_ignore_ascii_case(primary))
+}
+
+/// A language attribute, for shaping, hyphenation and font fallback.  This
+/// version of the pango crate has no constructor for one, so this is the one
+/// place that calls pango directly.
+fn language_attribute(lang: &str) -> pango::Attribute {
+    use glib::translate::{from_glib_full, ToGlibPtr};
+    let language = pango::Language::from_string(lang);
+    // SAFETY: pango_attr_language_new only reads the language, which pango
+    // interns for the life of the process, so the borrowed pointer is valid
+    // for the call.  It returns a newly allocated attribute (never null) that
+    // the caller owns, which from_glib_full takes over, so pango::Attribute
+    // frees it exactly once, on drop.
+    let attr: Option<pango::Attribute> = unsafe {
+        from_glib_full(pango_sys::pango_attr_language_new((&language).to_glib_none().0))
+    };
+    attr.unwrap()
+}
+
+/// Format `ft.text[start..end]` in `style`.
+fn add_style(ft: &mut FormattedText, style: TextStyle, start: usize, end: usize) {
+    for mut attr in style.attributes() {
+        attr.set_start_index(start as u32);
+        attr.set_end_index(end as u32);
+        ft.formatting.change(attr);
+    }
+    ft.spans.push(Span { style, start, end });
+}
+
+/// What part a line plays in its verse.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LineKind {
+    Normal,
+    /// A chorus line sung within a verse: ⟦chorus …⟧ in ⟦inline-chorus⟧
+    /// songs.
+    InlineChorus,
+    /// Where the chorus is sung mid-verse: ⟦Chorus⟧ on a line of its own in
+    /// ⟦inline-chorus-markers⟧ songs.  The text is the label to show.
+    ChorusMarker,
+}
+
+/// A footnote pulled out of the text it annotates.
+#[derive(Debug)]
+struct Footnote {
+    /// Byte offset of the footnote's marker in the annotated text.
+    anchor: usize,
+    /// The symbol marking the footnote, e.g. "*".  Empty until the song's
+    /// footnotes have been numbered.
+    marker: String,
+    text: FormattedText,
+}
+
 #[derive(Debug)]
 struct Error (String);
 
@@ -213,7 +1450,7 @@ impl From<io::Error> for Error {
 
 impl From<String> for Error {
     fn from(message: String) -> Error {
-        Error(message.into())
+        Error(message)
     }
 }
 
@@ -229,46 +1466,176 @@ fn tr_song(src: &Vec<Vec<Line>>) -> Result<Song, Error> {
     let verses = i.map(tr_verse).collect::<Result<_, _>>()?;
     let mut song = Song { meta, verses };
     normalize_indents(&mut song);
+    number_footnotes(&mut song);
+    name_choruses(&mut song);
+    check_line_kinds(&song)?;
+    check_section_refs(&song)?;
+    check_order(&song)?;
+    check_parallel(&song)?;
 
     Ok(song)
 }
 
+/// The default name for a chorus, until `name_choruses` finds a better one.
+const CHORUS: &str = "Chorus";
+
+/// "Chorus" in other languages, by primary language.
+const CHORUS_WORDS: &[(&str, &str)] = &[
+    ("cy", "Cytgan"),
+    ("de", "Refrain"),
+    ("es", "Coro"),
+    ("fr", "Refrain"),
+    ("ga", "Curfá"),
+    ("gd", "Sèist"),
+    ("it", "Ritornello"),
+    ("nl", "Refrein"),
+    ("pt", "Refrão"),
+];
+
+/// Call the chorus and every ⟦Chorus⟧ marker what the song calls it: the
+/// label of its ⟦Chorus: …⟧ if that has one, or else "Chorus" in the
+/// song's language.
+fn name_choruses(song: &mut Song) {
+    let labelled = song.verses.iter().filter_map(|verse| match *verse {
+        Verse::ChorusDef(ref label, _) if label != CHORUS => Some(label.clone()),
+        _ => None,
+    }).next();
+    let name = labelled.unwrap_or_else(|| {
+        let lang = song.lang().unwrap_or("en");
+        let primary = lang.split('-').next().unwrap_or(lang).to_lowercase();
+        CHORUS_WORDS.iter().find(|&&(l, _)| l == primary)
+            .map_or(CHORUS, |&(_, word)| word).to_string()
+    });
+    for verse in &mut song.verses {
+        match *verse {
+            Verse::ChorusDef(ref mut label, _) | Verse::ChorusRef(ref mut label)
+                if label == CHORUS => *label = name.clone(),
+            _ => {},
+        }
+        for line in verse_lines_mut(verse).into_iter().flatten() {
+            if line.kind == LineKind::ChorusMarker {
+                line.text = name.clone();
+            }
+        }
+    }
+}
+
 fn tr_meta_block(src: &Vec<Line>) -> Result<Vec<Metadata>, Error> {
-    src.iter()
-        .flat_map(|l| &l.items)
-        .filter_map(|item| {
-            match *item {
-                Item::Text(ref s) if str_is_whitespace(s) =>
-                    None,
-                Item::Text(ref s) =>
-                    Some(Err(Error::new(
-                        format!("Text in the meta block: {:?}", s)))),
-                Item::Sexp(ref sexp) =>
-                    Some(tr_meta_entry(sexp)),
+    let mut meta = Vec::new();
+    // Entries that add to a cross-reference, applied once we've seen it.
+    let mut details = Vec::new();
+    for item in src.iter().flat_map(|l| &l.items) {
+        match *item {
+            Item::Text(s) if str_is_whitespace(s) => {},
+            Item::Text(s) => return Err(Error::new(
+                format!("Text in the meta block: {:?}", s))),
+            Item::Sexp(ref sexp @ Sexp { keyword: "white-book-title", .. }) |
+            Item::Sexp(ref sexp @ Sexp { keyword: "white-book-note", .. }) =>
+                details.push(sexp),
+            Item::Sexp(ref sexp) => meta.push(tr_meta_entry(sexp)?),
+        }
+    }
+    for sexp in details {
+        add_cross_ref_detail(&mut meta, sexp)?;
+    }
+    Ok(meta)
+}
+
+/// Apply ⟦white-book-title⟧ or ⟦white-book-note⟧ to the song's (last)
+/// White Book reference, creating one without a number if there is none.
+fn add_cross_ref_detail(meta: &mut Vec<Metadata>, sexp: &Sexp)
+-> Result<(), Error> {
+    let book = ref_book("white-book").unwrap();
+    let has_ref = meta.iter().any(|m| match *m {
+        Metadata::CrossRef(ref r) => r.book.id == book.id,
+        _ => false,
+    });
+    if !has_ref {
+        meta.push(Metadata::CrossRef(CrossRef::new(book, None)));
+    }
+    let cross_ref = meta.iter_mut().rev().filter_map(|m| match *m {
+        Metadata::CrossRef(ref mut r) if r.book.id == book.id => Some(r),
+        _ => None,
+    }).next().unwrap();
+
+    let text = tr_meta_text(sexp)?;
+    let slot = match sexp.keyword {
+        "white-book-title" => &mut cross_ref.title,
+        _ => &mut cross_ref.note,
+    };
+    if slot.is_some() {
+        return Err(Error::new(format!("Duplicate {}", sexp)));
+    }
+    *slot = Some(text);
+    Ok(())
+}
+
+/// ⟦ref⟧ is free text, unless it starts with the id of a known book, as in
+/// ⟦ref white-book 123⟧.
+fn tr_ref(sexp: &Sexp) -> Result<Metadata, Error> {
+    if let Some(s) = sexp.opt_string_arg()? {
+        let mut words = s.splitn(2, ' ');
+        if let (Some(id), Some(number)) = (words.next(), words.next()) {
+            if let Some(book) = ref_book(id) {
+                let number = number.trim().to_string();
+                return Ok(Metadata::CrossRef(CrossRef::new(book, Some(number))));
             }
-        })
-        .collect()
+        }
+    }
+    Ok(Metadata::FreeRef(tr_meta_text(sexp)?))
 }
 
 fn str_is_whitespace(s: &str) -> bool {
     s.chars().all(char::is_whitespace)
 }
 
+/// The title.  A ⟦descant⟧ is sung over a line of a verse, so there's
+/// nowhere for one to go here.
+fn tr_title(sexp: &Sexp) -> Result<FormattedText, Error> {
+    let text = tr_formatted_text(&sexp.items)?;
+    if text.descant.is_some() {
+        return Err(Error::new(format!("A descant must be in a verse: {}", sexp)));
+    }
+    Ok(text)
+}
+
+/// The text of a meta entry other than the title.  Only the title's and
+/// the verses' footnotes are printed, so there mustn't be any here.
+fn tr_meta_text(sexp: &Sexp) -> Result<FormattedText, Error> {
+    let text = tr_title(sexp)?;
+    if !text.footnotes.is_empty() {
+        return Err(Error::new(format!(
+            "Footnotes are only allowed in the title and verses: {}", sexp)));
+    }
+    Ok(text)
+}
+
 fn tr_meta_entry(sexp: &Sexp) -> Result<Metadata, Error> {
     match sexp.keyword {
-        "title" => Ok(Metadata::Title(tr_formatted_text(&sexp.items)?)),
-        "alt-title" => Ok(Metadata::AltTitle(tr_formatted_text(&sexp.items)?)),
-        "attrib" => Ok(Metadata::Attrib(tr_formatted_text(&sexp.items)?)),
-        "ref" => Ok(Metadata::CrossRef(tr_formatted_text(&sexp.items)?)),
+        "title" => Ok(Metadata::Title(tr_title(sexp)?)),
+        "alt-title" => Ok(Metadata::AltTitle(tr_meta_text(sexp)?)),
+        "attrib" => Ok(Metadata::Attrib(tr_meta_text(sexp)?)),
+        "ref" => tr_ref(sexp),
+        "white-book" => {
+            let number = tr_meta_text(sexp)?.text.trim().to_string();
+            let book = ref_book("white-book").unwrap();
+            Ok(Metadata::CrossRef(CrossRef::new(book, Some(number))))
+        },
 
-        // To be translated
-        "white-book" => Ok(Metadata::CrossRef(tr_formatted_text(&sexp.items)?)),
-        "white-book-title" => Ok(Metadata::CrossRef(tr_formatted_text(&sexp.items)?)),
-        "author" => Ok(Metadata::Attrib(tr_formatted_text(&sexp.items)?)),
+        "author" => tr_credit(Role::Words, sexp),
+        "words" => tr_credit(Role::Words, sexp),
+        "music" => tr_credit(Role::Music, sexp),
+        "arrangement" => tr_credit(Role::Arrangement, sexp),
+        "translation" => tr_credit(Role::Translation, sexp),
+        "traditional" => tr_credit(Role::Traditional, sexp),
 
+        "copyright" => Ok(Metadata::Copyright(tr_meta_text(sexp)?)),
+        "license" => Ok(Metadata::License(sexp.string_arg()?.into())),
+        "ccli" => Ok(Metadata::Ccli(sexp.string_arg()?.trim().into())),
         "category" => Ok(Metadata::Category(sexp.string_arg()?.into())),
         "index" => Ok(Metadata::IndexEntry(sexp.string_arg()?.into())),
         "lang" => Ok(Metadata::Language(sexp.string_arg()?.into())),
+        "file-as" => Ok(Metadata::FileAs(sexp.string_arg()?.trim().into())),
         "dance" => Ok(Metadata::Dance(sexp.string_arg()?.into())),
         "descant" => {
             if sexp.has_args() {
@@ -277,16 +1644,42 @@ fn tr_meta_entry(sexp: &Sexp) -> Result<Metadata, Error> {
             }
             Ok(Metadata::Descant)
         },
-        "numbered-verses" => Ok(Metadata::Ignored),
-        "todo" => Ok(Metadata::Ignored),
-        "TODO" => Ok(Metadata::Ignored),
-        "TODO-special-formatting" => Ok(Metadata::Ignored),
-        "note" => Ok(Metadata::Ignored),
-        "inline-chorus-markers" => Ok(Metadata::Ignored),
-        "inline-chorus" => Ok(Metadata::Ignored),
-        "white-book-note" => Ok(Metadata::Ignored),
-        "origin" => Ok(Metadata::Ignored),
-        "source" => Ok(Metadata::Ignored),
+        "order" => {
+            let steps: Vec<String> = sexp.string_arg()?
+                .split_whitespace().map(String::from).collect();
+            if steps.is_empty() {
+                return Err(Error::new(format!("Empty order: {:?}", sexp)));
+            }
+            Ok(Metadata::Order(steps))
+        },
+        "parallel" => {
+            sexp.require_no_args()?;
+            Ok(Metadata::Parallel)
+        },
+        "numbered-verses" => {
+            sexp.require_no_args()?;
+            Ok(Metadata::NumberedVerses)
+        },
+        "todo" => Ok(Metadata::Todo(tr_meta_text(sexp)?)),
+        "TODO" => Ok(Metadata::Todo(tr_meta_text(sexp)?)),
+        "TODO-special-formatting" => {
+            let mut todo = tr_meta_text(sexp)?;
+            if todo.text.is_empty() {
+                todo.text.push_str("Special formatting");
+            }
+            Ok(Metadata::Todo(todo))
+        },
+        "note" => Ok(Metadata::Note(tr_meta_text(sexp)?)),
+        "inline-chorus-markers" => {
+            sexp.require_no_args()?;
+            Ok(Metadata::InlineChorusMarkers)
+        },
+        "inline-chorus" => {
+            sexp.require_no_args()?;
+            Ok(Metadata::InlineChorus)
+        },
+        "origin" => Ok(Metadata::Origin(tr_meta_text(sexp)?)),
+        "source" => Ok(Metadata::Source(tr_meta_text(sexp)?)),
         k => Err(Error::new(format!("Unrecognized meta keyword {:?}", k))),
     }
 }
@@ -299,23 +1692,307 @@ enum Metadata {
     AltTitle(FormattedText),
     /// RFC5646 language tag: the language of the text.
     Language(String),
+    /// How to alphabetize the song, if not by its title.
+    FileAs(String),
     /// Reference to another book containing the song.
-    CrossRef(FormattedText),
+    CrossRef(CrossRef),
+    /// A free-form reference to another book containing the song.
+    FreeRef(FormattedText),
     /// Attribution.
     Attrib(FormattedText),
+    /// Someone who wrote, composed, arranged or translated the song.
+    Credit(Credit),
+    /// The copyright notice, as printed.
+    Copyright(FormattedText),
+    /// The terms we use the song under, e.g. "public-domain" or "CCLI".
+    License(String),
+    /// The song's CCLI number, for licensing reports.
+    Ccli(String),
+    /// Where the song comes from, e.g. a tradition or an occasion.
+    Origin(FormattedText),
+    /// Where we got this version of the song.
+    Source(FormattedText),
+    /// An editor's note.
+    Note(FormattedText),
+    /// Something still to be done to this song file.
+    Todo(FormattedText),
     /// This song has a descant (somewhere).
     Descant,
+    /// Print numbers beside the verses.
+    NumberedVerses,
+    /// Verses may contain chorus lines, marked ⟦chorus …⟧.
+    InlineChorus,
+    /// Verses may contain ⟦Chorus⟧ lines, marking where the chorus is sung.
+    InlineChorusMarkers,
+    // These three are checked, but no output uses them yet.
     /// The category in which to file this song.
+    #[allow(dead_code)]
     Category(String),
     /// Additional phrases under which to index this song.
+    #[allow(dead_code)]
     IndexEntry(String),
     /// A type of dance this song may be suitable for.
+    #[allow(dead_code)]
     Dance(String),
-    Ignored,
+    /// The order verses are performed in, e.g. ⟦order V1 C V2 C B C⟧.
+    Order(Vec<String>),
+    /// Print the sections either side of the section break side by side.
+    Parallel,
+}
+
+/// What a credited person did.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Role {
+    Words,
+    Music,
+    Arrangement,
+    Translation,
+    /// No known author; the credit's qualifier may say where it's from.
+    Traditional,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Role::Words => "Words",
+            Role::Music => "Music",
+            Role::Arrangement => "Arr.",
+            Role::Translation => "Trans.",
+            Role::Traditional => "Traditional",
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Person {
+    name: String,
+    /// The name as written, with any formatting, for the credit line.
+    formatted: FormattedText,
+    /// How to alphabetize the name, e.g. "Wesley, Charles".
+    sort_key: String,
+}
+
+impl Person {
+    fn new(formatted: FormattedText, sort_key: Option<String>) -> Person {
+        let name = formatted.text.clone();
+        let sort_key = sort_key.unwrap_or_else(|| {
+            // Without ⟦sort-as⟧, guess that the last word is the surname.
+            match name.rfind(' ') {
+                Some(i) => format!("{}, {}", &name[i + 1..], &name[..i]),
+                None => name.clone(),
+            }
+        });
+        Person { name, formatted, sort_key }
+    }
+}
+
+/// ⟦words Charles Wesley ⟦year 1739⟧⟧ and the like.
+#[derive(Debug)]
+struct Credit {
+    role: Role,
+    /// Who; None for ⟦traditional⟧.
+    person: Option<Person>,
+    year: Option<String>,
+    /// Where a traditional song is from, as in ⟦traditional Irish⟧.
+    qualifier: Option<String>,
+}
+
+impl Credit {
+    /// The credit as it reads in the credit line, keeping any formatting in
+    /// the name.
+    fn formatted(&self) -> FormattedText {
+        let mut ft = FormattedText::new();
+        match self.person {
+            Some(ref person) => ft.append(&person.formatted),
+            None => ft.text.push_str(&self.role.to_string()),
+        }
+        if let Some(ref qualifier) = self.qualifier {
+            ft.text.push_str(&format!(" ({})", qualifier));
+        }
+        if let Some(ref year) = self.year {
+            ft.text.push_str(&format!(", {}", year));
+        }
+        ft
+    }
+}
+
+impl fmt::Display for Credit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.formatted().text)
+    }
+}
+
+/// The standard credit line: credits with the same role are grouped, as in
+/// "Words: Charles Wesley, 1739; Music: Felix Mendelssohn, 1840".
+fn credit_line(credits: &[&Credit]) -> Option<FormattedText> {
+    let mut groups: Vec<(Role, Vec<&Credit>)> = Vec::new();
+    for &credit in credits {
+        match groups.iter_mut().find(|g| g.0 == credit.role) {
+            Some(group) => group.1.push(credit),
+            None => groups.push((credit.role, vec![credit])),
+        }
+    }
+    if groups.is_empty() {
+        return None;
+    }
+    let mut line = FormattedText::new();
+    for (i, (role, credits)) in groups.into_iter().enumerate() {
+        if i > 0 {
+            line.text.push_str("; ");
+        }
+        if role != Role::Traditional {
+            line.text.push_str(&format!("{}: ", role));
+        }
+        for (j, credit) in credits.into_iter().enumerate() {
+            if j > 0 {
+                line.text.push_str(", ");
+            }
+            line.append(&credit.formatted());
+        }
+    }
+    Some(line)
+}
+
+fn tr_credit(role: Role, sexp: &Sexp) -> Result<Metadata, Error> {
+    let mut name = Vec::new();
+    let mut year = None;
+    let mut sort_key = None;
+    for item in &sexp.items {
+        match *item {
+            Item::Sexp(ref sub @ Sexp { keyword: "year", .. }) =>
+                year = Some(sub.string_arg()?.to_string()),
+            Item::Sexp(ref sub @ Sexp { keyword: "sort-as", .. }) =>
+                sort_key = Some(sub.string_arg()?.to_string()),
+            // Formatting in the name is kept, as in any other meta entry.
+            ref item => name.push(item.clone()),
+        }
+    }
+    let name = tr_formatted_text(&trim_items(name))?;
+    if !name.footnotes.is_empty() {
+        return Err(Error::new(format!("Footnotes aren't allowed in credits: {}", sexp)));
+    }
+    let text = if name.text.is_empty() { None } else { Some(name) };
+
+    let (person, qualifier) = match (role, text) {
+        (Role::Traditional, qualifier) => (None, qualifier.map(|q| q.text)),
+        (_, Some(name)) => (Some(Person::new(name, sort_key.take())), None),
+        (_, None) => return Err(Error::new(format!(
+            "A name is required for {}", sexp))),
+    };
+    if sort_key.is_some() {
+        return Err(Error::new(format!(
+            "⟦sort-as⟧ needs a name to apply to: {}", sexp)));
+    }
+    Ok(Metadata::Credit(Credit { role, person, year, qualifier }))
+}
+
+/// `items` without the whitespace at either end of their text.
+fn trim_items(mut items: Vec<Item>) -> Vec<Item> {
+    while let Some(&Item::Text(s)) = items.last() {
+        if !str_is_whitespace(s) {
+            break;
+        }
+        items.pop();
+    }
+    if let Some(&mut Item::Text(ref mut s)) = items.last_mut() {
+        *s = s.trim_end();
+    }
+    let blank = items.iter().take_while(|item| match **item {
+        Item::Text(s) => str_is_whitespace(s),
+        _ => false,
+    }).count();
+    items.drain(..blank);
+    if let Some(&mut Item::Text(ref mut s)) = items.first_mut() {
+        *s = s.trim_start();
+    }
+    items
+}
+
+/// A songbook we keep cross-references to.
+#[derive(Debug)]
+struct RefBook {
+    /// The name used in song files, as in ⟦ref white-book 123⟧.
+    id: &'static str,
+    name: &'static str,
+}
+
+const REF_BOOKS: [RefBook; 1] = [
+    RefBook { id: "white-book", name: "White Book" },
+];
+
+fn ref_book(id: &str) -> Option<&'static RefBook> {
+    REF_BOOKS.iter().find(|book| book.id == id)
+}
+
+/// Where the song appears in another songbook.
+#[derive(Debug)]
+struct CrossRef {
+    book: &'static RefBook,
+    /// The song's page or number in that book, if known.
+    number: Option<String>,
+    /// The song's title in that book, if it differs from ours.
+    title: Option<FormattedText>,
+    note: Option<FormattedText>,
+}
+
+impl CrossRef {
+    fn new(book: &'static RefBook, number: Option<String>) -> CrossRef {
+        CrossRef { book, number, title: None, note: None }
+    }
+}
+
+impl fmt::Display for CrossRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.book.name)?;
+        if let Some(ref number) = self.number {
+            write!(f, " #{}", number)?;
+        }
+        if let Some(ref title) = self.title {
+            write!(f, " “{}”", title.text)?;
+        }
+        if let Some(ref note) = self.note {
+            write!(f, " ({})", note.text)?;
+        }
+        Ok(())
+    }
 }
 
 
+/// A verse may start with a ⟦lang fr⟧ line of its own, giving the language
+/// of the whole verse.
+/// The language of a verse from a ⟦lang TAG⟧ line above it.  A first line
+/// that's a longer ⟦lang …⟧ span, like ⟦lang la Gloria in excelsis Deo⟧, is
+/// sung, and so part of the verse.
+fn verse_lang_tag<'a>(src: &'a [Line<'a>]) -> Option<&'a str> {
+    if src.len() < 2 {
+        return None;
+    }
+    match sole_sexp(&src[0]) {
+        Some(&Sexp { keyword: "lang", ref items }) => match items[..] {
+            [Item::Text(tag)] if !tag.trim().is_empty() && !tag.trim().contains(' ') =>
+                Some(tag.trim()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
 fn tr_verse(src: &Vec<Line>) -> Result<Verse, Error> {
+    let lang = match verse_lang_tag(src) {
+        Some(lang) => lang.to_string(),
+        None => return tr_verse_body(src),
+    };
+    let mut verse = tr_verse_body(&src[1..])?;
+    if let Some(lines) = verse_lines_mut(&mut verse) {
+        for line in lines {
+            let end = line.text.len();
+            add_style(line, TextStyle::Language(lang.clone()), 0, end);
+        }
+    }
+    Ok(verse)
+}
+
+fn tr_verse_body(src: &[Line]) -> Result<Verse, Error> {
     // A verse is normally just lines of formatted text.  But special verse
     // types may be introduced by a special sexp.  For easier parsing, we
     // expect that to be on a line by itself (ignoring whitespace).
@@ -327,9 +2004,8 @@ fn tr_verse(src: &Vec<Line>) -> Result<Verse, Error> {
         let line = i.peek().unwrap(); // The parser won't produce empty verses.
 
         // Ignore whitespace when looking for the meta sexp.
-        line.items.iter().filter(|item| match **item {
-            Item::Text(s) if str_is_whitespace(s) => false,
-            _ => true,
+        line.items.iter().filter(|item| {
+            !matches!(**item, Item::Text(s) if str_is_whitespace(s))
         })
     };
 
@@ -344,26 +2020,47 @@ fn tr_verse(src: &Vec<Line>) -> Result<Verse, Error> {
     match *item {
         Item::Sexp(ref sexp @ Sexp { keyword: "Chorus:", .. }) => {
             i.next();
-            let label = sexp.opt_string_arg()?.unwrap_or("Chorus").to_string();
+            let label = sexp.opt_string_arg()?.as_deref().unwrap_or(CHORUS).to_string();
             Ok(Verse::ChorusDef(label, tr_lines(i)?))
         },
         Item::Sexp(ref sexp @ Sexp { keyword: "Refrain:", .. }) => {
             i.next();
-            let label = sexp.opt_string_arg()?.unwrap_or("Refrain").to_string();
+            let label = sexp.opt_string_arg()?.as_deref().unwrap_or("Refrain").to_string();
             Ok(Verse::RefrainDef(label, tr_lines(i)?))
         },
-        Item::Sexp(ref sexp @ Sexp { keyword: "Chorus", .. }) => {
+        // With more lines, this is a chorus marker at the start of a verse.
+        Item::Sexp(ref sexp @ Sexp { keyword: "Chorus", .. }) if src.len() == 1 => {
             i.next();
             verse_requires_no_lines(i)?;
             sexp.require_no_args()?;
-            let label = String::from("Chorus");
-            Ok(Verse::ChorusRef(label))
+            Ok(Verse::ChorusRef(CHORUS.to_string()))
         },
         Item::Sexp(ref sexp @ Sexp { keyword: "section-break", .. }) => {
             i.next();
-            verse_requires_no_lines(i)?;
             let label = sexp.string_arg()?.to_string();
-            Ok(Verse::SectionBreak(label))
+            let lang = match i.next() {
+                Some(line) => match sole_sexp(line) {
+                    Some(lang @ &Sexp { keyword: "lang", .. }) =>
+                        Some(lang.string_arg()?.trim().to_string()),
+                    _ => return Err(Error::new(format!(
+                        "Only a ⟦lang⟧ line may follow a section break: {:?}", line))),
+                },
+                None => None,
+            };
+            verse_requires_no_lines(i)?;
+            Ok(Verse::SectionBreak(label, lang))
+        },
+        Item::Sexp(ref sexp) if section_keyword(sexp.keyword).is_some() => {
+            i.next();
+            let (section, def) = section_keyword(sexp.keyword).unwrap();
+            let label = sexp.opt_string_arg()?
+                .map_or_else(|| section.to_string(), Cow::into_owned);
+            if def {
+                Ok(Verse::SectionDef(section, label, tr_lines(i)?))
+            } else {
+                verse_requires_no_lines(i)?;
+                Ok(Verse::SectionRef(section, label))
+            }
         },
         _ => {
             tr_normal_verse(i)
@@ -383,55 +2080,269 @@ where I: Iterator<Item = &'a Line<'a>> {
 
 fn tr_normal_verse<'a, I>(src: I) -> Result<Verse, Error>
 where I: Iterator<Item = &'a Line<'a>> {
-    let lines = src.map(tr_line).collect::<Result<_, _>>()?;
-    Ok(Verse::Normal(lines))
+    Ok(Verse::Normal(tr_lines(src)?))
 }
 
 fn tr_lines<'a, I>(src: I) -> Result<Vec<FormattedText>, Error>
 where I: Iterator<Item = &'a Line<'a>> {
-    src.map(tr_line).collect()
+    let mut src: Vec<&Line> = src.collect();
+
+    // ⟦×2⟧ on a line of its own at the end repeats the whole verse.
+    let verse_times = match src.last().and_then(|line| sole_sexp(line)) {
+        Some(sexp) => match times_keyword(sexp.keyword) {
+            Some(times) => {
+                sexp.require_no_args()?;
+                Some(times)
+            },
+            None => None,
+        },
+        None => None,
+    };
+    if let Some(times) = verse_times {
+        src.pop();
+        if src.is_empty() {
+            return Err(Error::new(format!("Nothing to sing ×{}", times)));
+        }
+    }
+
+    let mut lines = src.into_iter().map(tr_line).collect::<Result<Vec<_>, _>>()?;
+    if let Some(times) = verse_times {
+        // The repeat signs for the whole verse go on its first and last
+        // lines, which can't have signs of their own too.
+        if lines.first().unwrap().repeat_start || lines.last().unwrap().repeat_end.is_some() {
+            return Err(Error::new(format!(
+                "A verse sung ×{} can't also start a repeat on its first line \
+                 or end one on its last", times)));
+        }
+        lines.first_mut().unwrap().repeat_start = true;
+        lines.last_mut().unwrap().repeat_end = Some(times);
+    }
+    check_repeats(&lines)?;
+    Ok(lines)
+}
+
+/// The N in ⟦×N⟧ (or ⟦xN⟧), if N is at least 2.
+fn times_keyword(keyword: &str) -> Option<u32> {
+    let n = keyword.strip_prefix(['×', 'x'])?;
+    if !n.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    n.parse().ok().filter(|&n| n >= 2)
+}
+
+/// The count in ⟦end-repeat 3⟧ or ⟦end-repeat ×3⟧.
+fn repeat_count(arg: &str) -> Option<u32> {
+    let arg = arg.trim();
+    times_keyword(arg).or_else(|| arg.parse().ok().filter(|&n| n >= 2))
+}
+
+/// Repeated passages mustn't nest or overlap, and must end within the verse
+/// they start in.
+fn check_repeats(lines: &[FormattedText]) -> Result<(), Error> {
+    let mut open: Option<&FormattedText> = None;
+    for line in lines {
+        if line.repeat_start {
+            if let Some(start) = open {
+                return Err(Error::new(format!(
+                    "Repeat at {:?} starts inside the repeat at {:?}", line, start)));
+            }
+            open = Some(line);
+        }
+        if line.repeat_end.is_some() && open.take().is_none() {
+            return Err(Error::new(format!(
+                "⟦end-repeat⟧ at {:?} has no ⟦repeat⟧", line)));
+        }
+    }
+    match open {
+        Some(start) => Err(Error::new(format!(
+            "⟦repeat⟧ at {:?} isn't ended in its verse", start))),
+        None => Ok(()),
+    }
+}
+
+/// The lines of a verse as sung, with ⟦×2⟧ lines and repeated passages
+/// written out.
+fn sung_lines(lines: &[FormattedText]) -> Vec<&FormattedText> {
+    let mut sung = Vec::new();
+    let mut passage = 0;
+    for line in lines {
+        if line.repeat_start {
+            passage = sung.len();
+        }
+        for _ in 0..line.times {
+            sung.push(line);
+        }
+        if let Some(times) = line.repeat_end {
+            let repeated = sung[passage..].to_vec();
+            for _ in 1..times {
+                sung.extend(&repeated);
+            }
+        }
+    }
+    sung
 }
 
 fn tr_line(src: &Line) -> Result<FormattedText, Error> {
-    let mut ft = tr_formatted_text(&src.items)?;
+    // Repeat markup goes at the ends of the line, outside any other markup.
+    let mut items: Vec<&Item> = src.items.iter().collect();
+    let mut repeat_start = false;
+    let mut repeat_end = None;
+    let mut times = 1;
+    while let Some(at) = items.iter().position(|item| !item_is_whitespace(item)) {
+        match *items[at] {
+            Item::Sexp(ref sexp) if sexp.keyword == "repeat" && !sexp.has_args() => {
+                repeat_start = true;
+            },
+            _ => break,
+        }
+        items.remove(at);
+    }
+    while let Some(at) = items.iter().rposition(|item| !item_is_whitespace(item)) {
+        match *items[at] {
+            Item::Sexp(ref sexp) if sexp.keyword == "end-repeat" => {
+                repeat_end = Some(match sexp.opt_string_arg()? {
+                    Some(n) => repeat_count(&n).ok_or_else(|| Error::new(format!(
+                        "Bad repeat count: {}", sexp)))?,
+                    None => 2,
+                });
+            },
+            Item::Sexp(ref sexp) if times_keyword(sexp.keyword).is_some() => {
+                sexp.require_no_args()?;
+                times = times_keyword(sexp.keyword).unwrap();
+            },
+            _ => break,
+        }
+        items.remove(at);
+    }
+
+    let line = Line {
+        indent: src.indent,
+        items: items.into_iter().cloned().collect(),
+    };
+    let mut ft = match sole_sexp(&line) {
+        // ⟦repeat …⟧ around the words repeats just this line.
+        Some(sexp @ &Sexp { keyword: "repeat", .. }) => {
+            repeat_start = true;
+            repeat_end = repeat_end.or(Some(2));
+            let mut ft = tr_formatted_text(&sexp.items)?;
+            ft.indent = src.indent.len() as u32;
+            ft
+        },
+        _ => tr_plain_line(&line)?,
+    };
+    ft.times = times;
+    ft.repeat_start = repeat_start;
+    ft.repeat_end = repeat_end;
+    Ok(ft)
+}
+
+fn item_is_whitespace(item: &Item) -> bool {
+    match *item {
+        Item::Text(s) => str_is_whitespace(s),
+        _ => false,
+    }
+}
+
+fn tr_plain_line(src: &Line) -> Result<FormattedText, Error> {
+    let mut ft = match sole_sexp(src) {
+        Some(sexp @ &Sexp { keyword: "chorus", .. }) => {
+            let mut ft = tr_formatted_text(&sexp.items)?;
+            ft.kind = LineKind::InlineChorus;
+            ft
+        },
+        Some(sexp @ &Sexp { keyword: "Chorus", .. }) => {
+            sexp.require_no_args()?;
+            let mut ft = FormattedText::new();
+            ft.text.push_str(CHORUS);
+            ft.kind = LineKind::ChorusMarker;
+            ft
+        },
+        _ => tr_formatted_text(&src.items)?,
+    };
     ft.indent = src.indent.len() as u32;
     Ok(ft)
 }
 
-fn tr_formatted_text(src: &Vec<Item>) -> Result<FormattedText, Error> {
+/// The line's only item (ignoring whitespace), if that's a sexp.
+fn sole_sexp<'a>(line: &'a Line<'a>) -> Option<&'a Sexp<'a>> {
+    let mut items = line.items.iter().filter(|item| match **item {
+        Item::Text(s) => !str_is_whitespace(s),
+        _ => true,
+    });
+    match (items.next(), items.next()) {
+        (Some(Item::Sexp(sexp)), None) => Some(sexp),
+        _ => None,
+    }
+}
+
+fn tr_formatted_text(src: &[Item]) -> Result<FormattedText, Error> {
     let mut ft = FormattedText::new();
     add_formatted_text(src, &mut ft)?;
     Ok(ft)
 }
 
-fn add_formatted_text(src: &Vec<Item>, ft: &mut FormattedText)
+fn add_formatted_text(src: &[Item], ft: &mut FormattedText)
 -> Result<(), Error> {
     for item in src {
         match *item {
-            Item::Text(ref s) => ft.text.push_str(s),
-            Item::Sexp(Sexp{keyword: "italic", ref items}) |
-            Item::Sexp(Sexp{keyword: "note", ref items}) => {
-                // When would new_style ever return None???
-                let mut attr = pango::Attribute::new_style(pango::Style::Italic).unwrap();
-                attr.set_start_index(ft.text.len() as u32);
+            Item::Text(s) => ft.text.push_str(s),
+            Item::Sexp(Sexp{keyword, ref items})
+                if TextStyle::from_keyword(keyword).is_some() => {
+                let style = TextStyle::from_keyword(keyword).unwrap();
+                let start = ft.text.len();
                 add_formatted_text(items, ft)?;
-                attr.set_end_index(ft.text.len() as u32);
-                ft.formatting.change(attr);
+                let end = ft.text.len();
+                add_style(ft, style, start, end);
+            },
+            Item::Sexp(ref sexp @ Sexp{keyword: "lang", ..}) => {
+                // The tag is the first word: ⟦lang fr Bonjour⟧.
+                let (lang, first) = match sexp.items.first() {
+                    Some(&Item::Text(s)) => {
+                        let mut words = s.trim_start().splitn(2, ' ');
+                        (words.next().unwrap_or(""), words.next().unwrap_or(""))
+                    },
+                    _ => ("", ""),
+                };
+                if lang.is_empty() {
+                    return Err(Error::new(format!(
+                        "⟦lang⟧ needs a language tag first: {}", sexp)));
+                }
+                let start = ft.text.len();
+                ft.text.push_str(first);
+                add_formatted_text(&sexp.items[1..], ft)?;
+                let end = ft.text.len();
+                add_style(ft, TextStyle::Language(lang.to_string()), start, end);
             },
             Item::Sexp(Sexp{keyword: "footnote", ref items}) => {
-                // When would new_style ever return None???
-                let mut attr = pango::Attribute::new_style(pango::Style::Italic).unwrap();
-                attr.set_start_index(ft.text.len() as u32);
-                add_formatted_text(items, ft)?;
-                attr.set_end_index(ft.text.len() as u32);
-                ft.formatting.change(attr);
+                let text = tr_formatted_text(items)?;
+                if !text.footnotes.is_empty() {
+                    return Err(Error::new(format!(
+                        "Footnotes can't contain footnotes: {:?}", text)));
+                }
+                ft.footnotes.push(Footnote {
+                    anchor: ft.text.len(),
+                    marker: String::new(),
+                    text,
+                });
+            },
+            Item::Sexp(ref sexp @ Sexp{keyword: "descant", ..}) => {
+                if ft.descant.is_some() {
+                    return Err(Error::new(format!(
+                        "Only one descant per line: {}", sexp)));
+                }
+                let descant = tr_formatted_text(&sexp.items)?;
+                if descant.descant.is_some() {
+                    return Err(Error::new(format!(
+                        "Descants can't contain descants: {}", sexp)));
+                }
+                ft.descant = Some(Box::new(descant));
             },
             Item::Sexp(ref s @ Sexp{keyword: "...", ..}) => {
                 if !s.items.is_empty() {
                     return Err(Error::new(format!(
                         "⟦... {:?}⟧ should have no arguments", s.items)));
                 }
-                ft.text.push_str("…");
+                ft.text.push('…');
             },
             Item::Sexp(ref sexp) => return Err(Error::new(format!(
                 "Unrecognized formatting command '{}'", sexp))),
@@ -460,13 +2371,146 @@ fn normalize_indents(song: &mut Song) {
     }
 }
 
+/// Inline chorus lines and chorus markers need their song modes turned on,
+/// so that a stray ⟦chorus⟧ is caught rather than quietly restyled.
+fn check_line_kinds(song: &Song) -> Result<(), Error> {
+    let lines = song.verses.iter()
+        .filter_map(verse_lines)
+        .flat_map(|lines| lines.iter());
+    for line in lines {
+        let (mode, keyword) = match line.kind {
+            LineKind::Normal => continue,
+            LineKind::InlineChorus => (song.inline_chorus(), "inline-chorus"),
+            LineKind::ChorusMarker =>
+                (song.inline_chorus_markers(), "inline-chorus-markers"),
+        };
+        if !mode {
+            return Err(Error::new(format!(
+                "{:?} needs ⟦{}⟧ in the meta block", line, keyword)));
+        }
+    }
+    Ok(())
+}
+
+/// Every ⟦Bridge⟧ and the like must refer back to a section defined earlier
+/// in the song with the same label.
+fn check_section_refs(song: &Song) -> Result<(), Error> {
+    let mut defined = Vec::new();
+    for verse in &song.verses {
+        match *verse {
+            Verse::SectionDef(section, ref label, _) => defined.push((section, label)),
+            Verse::SectionRef(section, ref label)
+                if !defined.contains(&(section, label)) => {
+                return Err(Error::new(format!(
+                    "{} “{}” is sung before it's defined", section, label)));
+            },
+            _ => {},
+        }
+    }
+    Ok(())
+}
+
+/// A ⟦parallel⟧ song needs exactly one section break, with verses on both
+/// sides of it.
+fn check_parallel(song: &Song) -> Result<(), Error> {
+    if !song.parallel() {
+        return Ok(());
+    }
+    let breaks = song.verses.iter()
+        .filter(|verse| matches!(**verse, Verse::SectionBreak(_, _)))
+        .count();
+    let split = song.parallel_split().unwrap_or(0);
+    if breaks != 1 || split == 0 || split + 1 == song.verses.len() {
+        return Err(Error::new(
+            "⟦parallel⟧ needs one section break, between two sets of verses"
+            .to_string()));
+    }
+    Ok(())
+}
+
+/// Every step of the ⟦order⟧ must name a verse in the song.
+fn check_order(song: &Song) -> Result<(), Error> {
+    let orders = song.meta.iter().filter(|m| matches!(**m, Metadata::Order(_)));
+    if orders.count() > 1 {
+        return Err(Error::new("Duplicate ⟦order⟧".to_string()));
+    }
+    for step in song.order().unwrap_or(&[]) {
+        match song.order_step_matches(step).len() {
+            0 => return Err(Error::new(format!(
+                "⟦order⟧ step {} doesn't match any verse", step))),
+            1 => {},
+            _ => return Err(Error::new(format!(
+                "⟦order⟧ step {} is ambiguous, since verse numbers start \
+                 again after a section break", step))),
+        }
+    }
+    Ok(())
+}
+
+/// Give each footnote a marker, in order through the song, and insert the
+/// markers into the annotated text.
+fn number_footnotes(song: &mut Song) {
+    let mut count = 0;
+    let titles = song.meta.iter_mut().filter_map(|m| match *m {
+        Metadata::Title(ref mut t) => Some(t),
+        _ => None,
+    });
+    let lines = song.verses.iter_mut()
+        .filter_map(verse_lines_mut)
+        .flat_map(|lines| lines.iter_mut());
+    for ft in titles.chain(lines) {
+        number_footnotes_in(ft, &mut count);
+        if let Some(ref mut descant) = ft.descant {
+            number_footnotes_in(descant, &mut count);
+        }
+    }
+}
+
+fn number_footnotes_in(ft: &mut FormattedText, count: &mut usize) {
+    let mut shift = 0;
+    let mut footnotes = std::mem::take(&mut ft.footnotes);
+    for footnote in &mut footnotes {
+        footnote.anchor += shift;
+        footnote.marker = footnote_marker(*count);
+        insert_superscript(ft, footnote.anchor, &footnote.marker);
+        shift += footnote.marker.len();
+        *count += 1;
+    }
+    ft.footnotes = footnotes;
+}
+
+const FOOTNOTE_SYMBOLS: [&str; 6] = ["*", "†", "‡", "§", "‖", "¶"];
+
+/// The marker for the nth footnote: *, †, ‡, …, then **, ††, and so on.
+fn footnote_marker(n: usize) -> String {
+    let symbol = FOOTNOTE_SYMBOLS[n % FOOTNOTE_SYMBOLS.len()];
+    symbol.repeat(n / FOOTNOTE_SYMBOLS.len() + 1)
+}
+
+fn insert_superscript(ft: &mut FormattedText, at: usize, s: &str) {
+    let superscript = pango::AttrList::new();
+    for mut attr in TextStyle::Superscript.attributes() {
+        attr.set_end_index(s.len() as u32);
+        superscript.insert(attr);
+    }
+
+    ft.text.insert_str(at, s);
+    ft.formatting.splice(&superscript, at as i32, s.len() as i32);
+    for span in &mut ft.spans {
+        span.insert(at, s.len());
+    }
+    ft.spans.push(Span { style: TextStyle::Superscript, start: at, end: at + s.len() });
+}
+
 fn verse_lines(verse: &Verse) -> Option<&Vec<FormattedText>> {
     match *verse {
         Verse::Normal(ref lines) => Some(lines),
         Verse::ChorusDef(_, ref lines) => Some(lines),
         Verse::RefrainDef(_, ref lines) => Some(lines),
         Verse::ChorusRef(_) => None,
-        Verse::SectionBreak(_) => None,
+        Verse::SectionBreak(_, _) => None,
+        Verse::SectionDef(_, _, ref lines) => Some(lines),
+        Verse::SectionRef(_, _) => None,
     }
 }
 
@@ -476,6 +2520,216 @@ fn verse_lines_mut(verse: &mut Verse) -> Option<&mut Vec<FormattedText>> {
         Verse::ChorusDef(_, ref mut lines) => Some(lines),
         Verse::RefrainDef(_, ref mut lines) => Some(lines),
         Verse::ChorusRef(_) => None,
-        Verse::SectionBreak(_) => None,
+        Verse::SectionBreak(_, _) => None,
+        Verse::SectionDef(_, _, ref mut lines) => Some(lines),
+        Verse::SectionRef(_, _) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_in_tag_arguments() {
+        let song = parse_song("⟦title Brackets⟧\n⟦file-as a \\⟦b\\⟧ c⟧\n\nWords", 0).unwrap();
+        assert_eq!(song.file_as().unwrap(), "a ⟦b⟧ c");
+    }
+
+    #[test]
+    fn escapes_in_lyrics() {
+        let song = parse_song("⟦title Brackets⟧\n\nA \\[[b\\]] and \\\\", 0).unwrap();
+        let lines = verse_lines(&song.verses[0]).unwrap();
+        assert_eq!(lines[0].text, "A [[b]] and \\");
+    }
+
+    fn verse_text(source: &str) -> Vec<String> {
+        let song = parse_song(source, 0).unwrap();
+        verse_lines(&song.verses[0]).unwrap().iter().map(|ft| ft.text.clone()).collect()
+    }
+
+    #[test]
+    fn comment_lines_are_dropped() {
+        assert_eq!(verse_text("⟦title T⟧\n// about the song\n\nOne\n  // a note\nTwo"),
+                   ["One", "Two"]);
+        assert!(parse_song("// just\n\n// comments", 0).is_err());
+    }
+
+    #[test]
+    fn comment_tags_are_dropped() {
+        assert_eq!(verse_text("⟦title T⟧\n\nOne ⟦// a note⟧\nA ⟦// b⟧ B\n⟦// c⟧ Three"),
+                   ["One", "A B", "Three"]);
+        assert_eq!(verse_text("⟦title T⟧\n\n⟦bold bold ⟦// note⟧⟧ text"), ["bold text"]);
+    }
+
+    #[test]
+    fn slashes_in_lyrics_are_text() {
+        assert_eq!(verse_text("⟦title T⟧\n\nLyrics // with slashes\n\\// leading"),
+                   ["Lyrics // with slashes", "// leading"]);
+    }
+
+    #[test]
+    fn sources_are_normalized() {
+        let (text, normalized) = normalize_source("\u{feff}⟦title T⟧\r\n  \r\n\nA \r\nB\u{a0}");
+        assert_eq!(text, "⟦title T⟧\n\n\nA\nB\u{a0}");
+        assert!(normalized.bom);
+        assert_eq!(normalized.crlf, 3);
+        assert_eq!(normalized.blank_lines, 1);
+        assert_eq!(normalized.trailing_whitespace, 1);
+        assert!(normalize_source("A\nB").1.is_empty());
+
+        let (text, normalized) = normalize_source("A\n \t \n\t\nB \t");
+        assert_eq!(text, "A\n\n\nB \t");
+        assert_eq!(normalized.blank_lines, 2);
+        assert!(check_tabs(&text[..4], 0).is_ok());
+        assert!(check_tabs(&text, 0).is_err());
+    }
+
+    #[test]
+    fn files_split_into_songs() {
+        assert_eq!(split_songs("A\n\nB"), [(0, "A\n\nB")]);
+        assert_eq!(split_songs("A\n===\nB\nC\n  ===  \nD"),
+                   [(0, "A\n"), (2, "B\nC\n"), (5, "D")]);
+        assert_eq!(split_songs("A\n===\n\n===\n"), [(0, "A\n")]);
+        assert_eq!(split_songs(""), [(0, "")]);
+    }
+
+    #[test]
+    fn errors_give_the_line_in_the_file() {
+        let e = parse_songs("⟦title A⟧\n\nOne\n===\n⟦title B⟧\n\nTwo ⟦\n").unwrap_err();
+        let e = format!("{}", e);
+        assert!(e.contains("Song 2: ") && e.contains("error at 7:"), "{}", e);
+    }
+
+    #[test]
+    fn songs_are_named_after_their_file() {
+        assert_eq!(song_name("carols.song", 0, 1), "carols.song");
+        assert_eq!(song_name("carols.song", 1, 3), "carols-2.song");
+        assert_eq!(song_name("carols", 0, 2), "carols-1");
+    }
+
+    /// A fresh directory for a test's files.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("songset-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn numbered_names_mustn_t_clash_with_files() {
+        let dir = test_dir("names");
+        fs::write(dir.join("carols.song"), "").unwrap();
+        assert_eq!(song_names(&dir.join("carols.song"), 2).unwrap(),
+                   ["carols-1.song", "carols-2.song"]);
+        fs::write(dir.join("carols-2.song"), "").unwrap();
+        assert!(song_names(&dir.join("carols.song"), 2).is_err());
+        assert!(song_names(&dir.join("carols.song"), 1).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parts_are_not_songs() {
+        assert!(is_song_source(Path::new("dir/carols.song")));
+        assert!(is_song_source(Path::new("dir/README")));
+        assert!(!is_song_source(Path::new("dir/chorus.part")));
+    }
+
+    #[test]
+    fn include_lines() {
+        assert_eq!(include_target("⟦include chorus.song⟧"), Some("chorus.song"));
+        assert_eq!(include_target("  [[include a b.song]] "), Some("a b.song"));
+        assert_eq!(include_target("⟦include chorus.song⟧ ⟦// shared⟧"), Some("chorus.song"));
+        assert_eq!(include_target("⟦include chorus.song⟧ and more"), None);
+        assert_eq!(include_target("// ⟦include chorus.song⟧"), None);
+        assert_eq!(include_target("⟦include⟧"), None);
+    }
+
+    #[test]
+    fn includes_are_read_and_cycles_caught() {
+        let dir = test_dir("includes");
+        fs::write(dir.join("song.song"), "⟦title T⟧\n\n⟦include chorus.part⟧\n").unwrap();
+        fs::write(dir.join("chorus.part"), "⟦Chorus:⟧\nLa la\n").unwrap();
+        assert_eq!(read_source(&dir.join("song.song")).unwrap(),
+                   "⟦title T⟧\n\n⟦Chorus:⟧\nLa la\n");
+
+        fs::write(dir.join("chorus.part"), "⟦include song.song⟧").unwrap();
+        let e = read_source(&dir.join("song.song")).unwrap_err();
+        assert!(format!("{}", e).contains("Include cycle"), "{}", e);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn articles_are_split_off() {
+        assert_eq!(split_article("The Lord's My Shepherd", "en"),
+                   Some(("The", "Lord's My Shepherd")));
+        assert_eq!(split_article("Theology", "en"), None);
+        assert_eq!(split_article("The", "en"), None);
+        assert_eq!(split_article("L’Amour", "fr-CA"), Some(("L’", "Amour")));
+        assert_eq!(split_article("Den Herrn", "de"), Some(("Den", "Herrn")));
+        assert_eq!(split_article("’R Arglwydd", "cy"), Some(("’R", "Arglwydd")));
+        assert_eq!(split_article("The Lord", "xx"), None);
+        // "İ" lowercases to two characters; the split mustn't move.
+        assert_eq!(split_article("İl Signore", "it"), None);
+    }
+
+    #[test]
+    fn footnote_markers_stay_on_the_page() {
+        let song = parse_song("⟦title Amazing⟦footnote First sung in 1773.⟧ Grace⟧\n\nLine", 0)
+            .unwrap();
+        assert_eq!(song.title().unwrap().text, "Amazing* Grace");
+        assert_eq!(song.plain_title().unwrap(), "Amazing Grace");
+        assert_eq!(song.file_as().unwrap(), "Amazing Grace");
+    }
+
+    #[test]
+    fn footnotes_only_in_the_title_and_verses() {
+        for entry in &["alt-title", "attrib", "copyright", "note", "origin", "source", "ref",
+                       "white-book-title", "white-book-note"] {
+            let source = format!("⟦title T⟧\n⟦{} A⟦footnote B⟧⟧\n\nLine", entry);
+            assert!(parse_song(&source, 0).is_err(), "{}", entry);
+        }
+    }
+
+    #[test]
+    fn verse_repeats_do_not_clash_with_line_repeats() {
+        let ok = parse_song("⟦title T⟧\n\nOne\nTwo\n⟦×2⟧", 0).unwrap();
+        let lines = verse_lines(&ok.verses[0]).unwrap();
+        assert!(lines[0].repeat_start);
+        assert_eq!(lines[1].repeat_end, Some(2));
+
+        for verse in &["⟦repeat One⟧\nTwo\n⟦×2⟧",
+                       "One\n⟦repeat⟧ Two ⟦end-repeat 3⟧\n⟦×2⟧",
+                       "⟦repeat⟧ One\nTwo ⟦end-repeat⟧\n⟦×2⟧"] {
+            let e = parse_song(&format!("⟦title T⟧\n\n{}", verse), 0).unwrap_err();
+            assert!(format!("{}", e).contains("A verse sung ×2"), "{}: {}", verse, e);
+        }
+    }
+
+    #[test]
+    fn verse_language_tags() {
+        let song = parse_song("⟦title T⟧\n\n⟦lang la⟧\nGloria\nin excelsis", 0).unwrap();
+        let lines = verse_lines(&song.verses[0]).unwrap();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].text, "Gloria");
+
+        // A first line in another language is part of the verse.
+        for first in &["⟦lang la Gloria in excelsis Deo⟧",
+                       "⟦lang la Gloria ⟦italic in excelsis⟧ Deo⟧"] {
+            let source = format!("⟦title T⟧\n\n{}\nGlory to God in the highest", first);
+            let song = parse_song(&source, 0).unwrap();
+            let lines = verse_lines(&song.verses[0]).unwrap();
+            assert_eq!(lines.len(), 2, "{}", first);
+            assert_eq!(lines[0].text, "Gloria in excelsis Deo");
+        }
+    }
+
+    #[test]
+    fn tabs_are_reported() {
+        assert!(check_tabs("no tabs\nhere", 0).is_ok());
+        assert!(check_tabs("  //\ta comment\nhere", 0).is_ok());
+        let e = check_tabs("fine\n  a\tb", 10).unwrap_err();
+        assert!(format!("{}", e).contains("line 12, column 4"), "{}", e);
     }
 }
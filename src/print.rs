@@ -1,13 +1,21 @@
-use std::path::Path as FsPath;
+use std::cell::{Cell, RefCell};
+use std::fs;
+use std::path::{Path as FsPath, PathBuf};
 
+use Footnote;
 use FormattedText;
+use LineKind;
 use Song;
 use Verse;
+use credit_line;
+use rtl_language;
+use verse_lines;
 
 extern crate cairo;
 extern crate pango;
 extern crate pangocairo;
 
+use pango::ContextExt;
 use pango::LayoutExt;
 
 use self::pangocairo::functions as pc;
@@ -22,7 +30,50 @@ const INDENT: Points = 24.0;
 const FONT_SIZE: i32 = 16; // points
 const MIN_FONT_SIZE: i32 = 13; // points
 const MARGIN_RIGHT: Points = 0.5 * 72.0;
+const MARGIN_BOTTOM: Points = 0.5 * 72.0;
 const GUTTER: Points = 18.0;
+const MAX_COLUMNS: u32 = 2;
+const FONT_STEP: Points = 0.5;
+const FRACTIONAL_FONT_STEP: Points = 0.1;
+const MAX_WRAPPED_LINES: u32 = 3;
+
+/// Bump this whenever a change to the renderer alters its output, so that
+/// the build cache knows to regenerate everything.
+pub const RENDERER_VERSION: u32 = 21;
+
+/// Options affecting how songs are laid out.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// Let the fitter choose font sizes between the usual half-point steps.
+    pub fractional_sizes: bool,
+    /// An editor's draft: print the songs' notes and to-dos.
+    pub draft: bool,
+    /// Print a song that doesn't fit even at the minimum size anyway,
+    /// running off the page, rather than failing.
+    pub allow_overflow: bool,
+    pub width_strategy: WidthStrategy,
+}
+
+/// What to do about lines too wide for the page.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum WidthStrategy {
+    /// Shrink the whole song until its widest line fits.
+    #[default]
+    Shrink,
+    /// Wrap a few over-long lines with a hanging indent, and only shrink the
+    /// song if that isn't enough.
+    Wrap,
+}
+
+/// A summary of the style settings that affect rendered output, for the
+/// build cache key.
+pub fn style_fingerprint(options: &Options) -> String {
+    format!("{}x{} indent={} font={}/{} margin={} gutter={} columns={} \
+             base={} {:?}",
+            PAGE_WIDTH, PAGE_HEIGHT, INDENT, FONT_SIZE, MIN_FONT_SIZE,
+            MARGIN_RIGHT, GUTTER, MAX_COLUMNS,
+            BASE_FONT.with(FontDescription::to_string), options)
+}
 
 fn points_from_inches(size: f64) -> f64 {
     size * 72.0
@@ -73,85 +124,481 @@ impl Default for Maximum {
     }
 }
 
-struct FontDesc(pango::FontDescription);
-unsafe impl Sync for FontDesc {}
-impl<'a> From<&'a FontDesc> for Option<&'a pango::FontDescription> {
-    fn from(d: &FontDesc) -> Option<&pango::FontDescription> {
-        Some(&d.0)
-    }
-}
-impl ::std::ops::Deref for FontDesc {
-    type Target = pango::FontDescription;
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
-}
-
-lazy_static! {
-    static ref BASE_FONT: FontDesc = {
+thread_local! {
+    // Pango objects can't be shared between threads, so each rendering
+    // thread builds its own fonts (and, via pangocairo, its own font map).
+    static BASE_FONT: FontDescription = {
         let mut font = pango::FontDescription::new();
         font.set_family("Caladea");
         font.set_absolute_size(pango_from_points(12.0));
-        FontDesc(font)
+        font
     };
 }
 
-pub fn pdf_song(path: &FsPath, song: &Song) -> Result<(), ()> {
+/// Facts about a rendered song, for the build manifest.
+#[derive(Debug)]
+pub struct Output {
+    pub pages: u32,
+    pub fit: Fit,
+}
+
+/// Print a song to a PDF at `path`.  If it doesn't fit, and overflowing
+/// isn't allowed, the file at `path` is left as it was.
+pub fn pdf_song(path: &FsPath, song: &Song, options: &Options)
+-> Result<Output, ()> {
+    // Written alongside, to replace the old PDF only once it's complete.
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    let result = draw_song(&partial, song, options);
+    if result.is_err() || fs::rename(&partial, path).is_err() {
+        let _ = fs::remove_file(&partial);
+        return Err(());
+    }
+    result
+}
+
+fn draw_song(path: &FsPath, song: &Song, options: &Options)
+-> Result<Output, ()> {
     let surface = cairo::PDFSurface::create(path, PAGE_WIDTH, PAGE_HEIGHT);
     let cr = cairo::Context::new(&surface);
 
-    cr.move_to(points_from_inches(1.5), points_from_inches(0.5));
-    draw_title(&cr, song);
+    let left = points_from_inches(1.5);
+    let top = points_from_inches(0.5);
+    let text_width = PAGE_WIDTH - left - MARGIN_RIGHT;
+    let rtl = song_is_rtl(song);
+    cr.move_to(left, top);
+    draw_title(&cr, song, text_width, rtl, Mode::Measure);
 
-    try_draw_verses(&cr, song)?;
+    // The footnotes go at the bottom of the page, and the verses get
+    // whatever space is left above them.
+    let footnotes = song.footnotes();
+    let footnotes_height = draw_footnotes(&cr, &footnotes, text_width, rtl,
+                                          Mode::Measure);
+    let notes = if options.draft { song.notes() } else { Vec::new() };
+    let notes_height = draw_notes(&cr, &notes, text_width, Mode::Measure);
+    let copyright_height = draw_copyright(&cr, song, text_width, Mode::Measure);
 
+    let (fit, placement) = fit_verses(&cr, song, options,
+                                      footnotes_height + notes_height
+                                      + copyright_height);
+    if fit.overflow && !options.allow_overflow {
+        return Err(());
+    }
+
+    cr.move_to(left, top);
+    draw_title(&cr, song, text_width, rtl, Mode::Paint);
+    let mut config = LayoutConfig::new(song, fit.font_size);
+    config.wrap = placement.wrap;
+    draw_verses(&cr, &config, text_width, &placement);
+
+    cr.move_to(left, PAGE_HEIGHT - MARGIN_BOTTOM - footnotes_height
+                     - notes_height - copyright_height);
+    draw_footnotes(&cr, &footnotes, text_width, rtl, Mode::Paint);
+    cr.rel_move_to(0.0, footnotes_height);
+    draw_notes(&cr, &notes, text_width, Mode::Paint);
+    cr.rel_move_to(0.0, notes_height);
+    draw_copyright(&cr, song, text_width, Mode::Paint);
+
+    draw_cross_refs(&cr, song);
     draw_file_letter(&cr, song);
     cr.show_page();
-    Ok(())
+    Ok(Output { pages: 1, fit })
 }
 
-lazy_static! {
-    static ref PAGING_FONT: FontDesc = {
-        let mut font = BASE_FONT.clone();
+thread_local! {
+    static PAGING_FONT: FontDescription = {
+        let mut font = BASE_FONT.with(FontDescription::clone);
         font.set_absolute_size(pango_from_points(12.0));
-        FontDesc(font)
+        font
     };
 }
 
 fn draw_file_letter(cr: &Cr, song: &Song) {
-    let layout = pc::create_layout(&cr).unwrap();
-    layout.set_font_description(&*PAGING_FONT);
+    let layout = pc::create_layout(cr).unwrap();
+    PAGING_FONT.with(|font| layout.set_font_description(font));
 
     let title = song.file_as().expect("Song requires a title");
-    let letter = title.chars().next().expect("Song needs a non-empty title");
+    let letter = first_letter(&layout, &title);
+    assert!(!letter.is_empty(), "Song needs a non-empty title");
 
-    layout.set_text(&letter.to_string());
+    layout.set_text(letter);
     let (width, _height) = layout.get_size();
     cr.move_to(PAGE_WIDTH - points_from_inches(0.5), points_from_inches(0.5));
     cr.rel_move_to(points_from_pango(-width), 0.0);
     pc::show_layout(cr, &layout);
 }
 
-lazy_static! {
-    static ref TITLE_FONT: FontDesc = {
-        let mut font = BASE_FONT.clone();
+/// The first letter of `text` as a reader sees it: up to the first place
+/// pango would put the cursor, so "É" spelt with a combining accent or a
+/// Devanagari conjunct like "क्ष" is one letter, but a ligature like "ﬁ"
+/// drawn for "fi" is still two.
+fn first_letter<'a>(layout: &pango::Layout, text: &'a str) -> &'a str {
+    layout.set_text(text);
+    // Step the cursor on from the start: leftwards in right-to-left text.
+    let direction = match pango::find_base_dir(text) {
+        pango::Direction::Rtl => -1,
+        _ => 1,
+    };
+    let mut end = 0;
+    loop {
+        let (index, trailing) = layout.move_cursor_visually(true, end as i32, 0,
+                                                            direction);
+        if index <= end as i32 || index as usize >= text.len() {
+            return text;
+        }
+        let index = index as usize;
+        end = text[index..].char_indices().nth(trailing as usize)
+            .map_or(text.len(), |(i, _)| index + i);
+        // This pango predates Unicode 15.1, which keeps a consonant after a
+        // virama in the same letter.
+        if !text[..end].ends_with(VIRAMAS) {
+            return &text[..end];
+        }
+    }
+}
+
+/// Viramas that join the consonants either side of them into a conjunct.
+const VIRAMAS: &[char] = &[
+    '\u{94d}', '\u{9cd}', '\u{acd}', '\u{b4d}', '\u{c4d}', '\u{d4d}',
+];
+
+thread_local! {
+    static TITLE_FONT: FontDescription = {
+        let mut font = BASE_FONT.with(FontDescription::clone);
         font.set_absolute_size(pango_from_points(20.0));
         font.set_weight(pango::Weight::Bold);
-        FontDesc(font)
+        font
     };
 }
 
-fn draw_title(cr: &Cr, song: &Song) {
-    let layout = pc::create_layout(&cr).unwrap();
-    layout.set_font_description(&*TITLE_FONT);
+thread_local! {
+    static SUBTITLE_FONT: FontDescription = {
+        let mut font = BASE_FONT.with(FontDescription::clone);
+        font.set_absolute_size(pango_from_points(14.0));
+        font.set_style(pango::Style::Italic);
+        font
+    };
+    static ATTRIB_FONT: FontDescription = {
+        let mut font = BASE_FONT.with(FontDescription::clone);
+        font.set_absolute_size(pango_from_points(11.0));
+        font
+    };
+    static MARGIN_NOTE_FONT: FontDescription = {
+        let mut font = BASE_FONT.with(FontDescription::clone);
+        font.set_absolute_size(pango_from_points(9.0));
+        font
+    };
+}
 
+/// Draw the title, then any alternative titles and attributions under it,
+/// against the right margin, `width` along, if the song reads right to left.
+fn draw_title(cr: &Cr, song: &Song, width: Points, rtl: bool, mode: Mode) {
     let title = song.title().expect("Song requires a title");
+    let Size(title_width, title_height) =
+        TITLE_FONT.with(|font| draw_text_line(cr, font, title, width, rtl, mode));
+    if song.has_descant() && mode == Mode::Paint {
+        if rtl {
+            draw_badge(cr, "descant", width - title_width, title_height, rtl);
+        } else {
+            draw_badge(cr, "descant", title_width, title_height, rtl);
+        }
+    }
+    cr.rel_move_to(0.0, title_height);
+
+    for alt_title in song.alt_titles() {
+        let height = SUBTITLE_FONT.with(|font| {
+            draw_text_line(cr, font, alt_title, width, rtl, mode)
+        }).height();
+        cr.rel_move_to(0.0, height);
+    }
+    let credits = credit_line(&song.credits());
+    for attrib in song.attributions().into_iter().chain(credits.as_ref()) {
+        let height = ATTRIB_FONT.with(|font| {
+            draw_text_line(cr, font, attrib, width, rtl, mode)
+        }).height();
+        cr.rel_move_to(0.0, height);
+    }
+    cr.rel_move_to(0.0, 0.5 * title_height);
+}
+
+const COPYRIGHT_GAP: Points = 6.0;
+
+/// Draw the copyright notice and CCLI number in small print.  Returns the
+/// height it takes, including the gap above it.
+fn draw_copyright(cr: &Cr, song: &Song, width: Points, mode: Mode) -> Points {
+    let mut text = FormattedText::new();
+    if let Some(copyright) = song.copyright() {
+        text.text = copyright.text.clone();
+        text.formatting = copyright.formatting.copy().unwrap();
+    }
+    if let Some(number) = song.ccli() {
+        if !text.text.is_empty() {
+            text.text.push_str("  ");
+        }
+        text.text.push_str(&format!("CCLI song #{}", number));
+    }
+    if text.text.is_empty() {
+        return 0.0;
+    }
+    COPYRIGHT_GAP + draw_small_print(cr, None, &text, width, COPYRIGHT_GAP, mode)
+}
+
+thread_local! {
+    static NOTES_LABEL_FONT: FontDescription = {
+        let mut font = MARGIN_NOTE_FONT.with(FontDescription::clone);
+        font.set_weight(pango::Weight::Bold);
+        font
+    };
+}
+
+/// Draw the "Notes" block of an editor's draft.  Returns its height,
+/// including the gap above it.
+fn draw_notes(cr: &Cr, notes: &[(&str, &FormattedText)], width: Points,
+              mode: Mode) -> Points {
+    if notes.is_empty() {
+        return 0.0;
+    }
+    let mut height = COPYRIGHT_GAP;
+    for &(label, note) in notes {
+        height += draw_small_print(cr, Some(label), note, width, height, mode);
+    }
+    height
+}
+
+/// Draw wrapped small print `y` points below the current point, optionally
+/// with a bold label in front.  Returns its height.
+fn draw_small_print(cr: &Cr, label: Option<&str>, text: &FormattedText,
+                    width: Points, y: Points, mode: Mode) -> Points {
+    let (x0, y0) = cr.get_current_point();
+    let mut x = 0.0;
+    if let Some(label) = label {
+        let layout = pc::create_layout(cr).unwrap();
+        NOTES_LABEL_FONT.with(|font| layout.set_font_description(font));
+        layout.set_text(&format!("{}: ", label));
+        cr.move_to(x0, y0 + y);
+        show_layout(cr, mode, &layout);
+        x = points_from_pango(layout.get_size().0);
+    }
+
+    let layout = pc::create_layout(cr).unwrap();
+    MARGIN_NOTE_FONT.with(|font| layout.set_font_description(font));
+    layout.set_wrap(pango::WrapMode::Word);
+    layout.set_width(pango_from_points(width - x) as i32);
+    layout.set_text(&text.text);
+    layout.set_attributes(&text.formatting);
+    cr.move_to(x0 + x, y0 + y);
+    show_layout(cr, mode, &layout);
+    cr.move_to(x0, y0);
+    points_from_pango(layout.get_size().1)
+}
+
+const BADGE_PADDING: Points = 2.0;
+
+/// Draw a small boxed label, like "descant", `x` points right of the current
+/// point and centred on a line `height` points tall.
+fn draw_badge(cr: &Cr, label: &str, x: Points, height: Points, rtl: bool) {
+    let layout = pc::create_layout(cr).unwrap();
+    MARGIN_NOTE_FONT.with(|font| layout.set_font_description(font));
+    layout.set_text(label);
+    let size: Size = layout.get_size().into();
+    let Size(w, h) = size.map(points_from_pango);
 
-    layout.set_text(&title.text);
-    layout.set_attributes(&title.formatting);
+    let (x0, y0) = cr.get_current_point();
+    // After the title, which is to its left in right-to-left text.
+    let left = if rtl {
+        x0 + x - 3.0 * BADGE_PADDING - w
+    } else {
+        x0 + x + 3.0 * BADGE_PADDING
+    };
+    let top = y0 + (height - h) / 2.0;
+    cr.move_to(left, top);
     pc::show_layout(cr, &layout);
-    let (_width, height) = layout.get_size();
-    cr.rel_move_to(0.0, 1.5 * points_from_pango(height));
+    cr.set_line_width(0.5);
+    cr.rectangle(left - BADGE_PADDING, top - BADGE_PADDING,
+                 w + 2.0 * BADGE_PADDING, h + 2.0 * BADGE_PADDING);
+    cr.stroke();
+    cr.move_to(x0, y0);
+}
+
+const CROSS_REF_SEPARATOR: Points = 12.0;
+
+/// Draw the song's cross-references along the bottom margin.  If they
+/// don't fit across the page, they take more rows, stacked upwards so the
+/// last row stays clear of the bottom edge.
+fn draw_cross_refs(cr: &Cr, song: &Song) {
+    let left = points_from_inches(1.5);
+    let width = PAGE_WIDTH - left - MARGIN_RIGHT;
+    let structured = song.cross_refs().into_iter().map(|cross_ref| {
+        let mut text = FormattedText::new();
+        text.text = cross_ref.to_string();
+        text
+    }).collect::<Vec<_>>();
+    let layouts = structured.iter().chain(song.free_refs()).map(|cross_ref| {
+        let layout = MARGIN_NOTE_FONT.with(|font| text_layout(cr, font, cross_ref));
+        // Even a single reference mustn't run off the page.
+        layout.set_width(pango_from_points(width) as i32);
+        layout.set_wrap(pango::WrapMode::Word);
+        layout
+    }).collect::<Vec<_>>();
+
+    // Each row is a list of (x, layout), and how tall it is.
+    let mut rows: Vec<(Vec<(Points, &pango::Layout)>, Points)> = Vec::new();
+    let mut x = width;
+    for layout in &layouts {
+        let Size(w, h) = Size::from(layout.get_size()).map(points_from_pango);
+        if x > 0.0 && x + w > width {
+            rows.push((Vec::new(), 0.0));
+            x = 0.0;
+        }
+        let row = rows.last_mut().unwrap();
+        row.0.push((x, layout));
+        row.1 = row.1.max(h);
+        x += w + CROSS_REF_SEPARATOR;
+    }
+
+    let above: Points = rows.iter().rev().skip(1).map(|row| row.1).sum();
+    let mut y = PAGE_HEIGHT - points_from_inches(0.35) - above;
+    for (row, height) in rows {
+        for (x, layout) in row {
+            cr.move_to(left + x, y);
+            pc::show_layout(cr, layout);
+        }
+        y += height;
+    }
+}
+
+/// Draw one paragraph of formatted text at the current point, without
+/// moving it.
+fn draw_text(cr: &Cr, font: &FontDescription, text: &FormattedText) -> Size {
+    let layout = text_layout(cr, font, text);
+    pc::show_layout(cr, &layout);
+    let size: Size = layout.get_size().into();
+    size.map(points_from_pango)
+}
+
+/// Draw text on a line of its own: at the current point, or if it reads
+/// right to left, ending `width` further on.
+fn draw_text_line(cr: &Cr, font: &FontDescription, text: &FormattedText,
+                  width: Points, rtl: bool, mode: Mode) -> Size {
+    let layout = text_layout(cr, font, text);
+    if rtl {
+        set_rtl(&layout);
+    }
+    let size: Size = layout.get_size().into();
+    let size = size.map(points_from_pango);
+    let (x, y) = cr.get_current_point();
+    if rtl {
+        cr.move_to(x + width - size.width(), y);
+    }
+    show_layout(cr, mode, &layout);
+    cr.move_to(x, y);
+    size
+}
+
+fn text_layout(cr: &Cr, font: &FontDescription, text: &FormattedText)
+-> pango::Layout {
+    let layout = pc::create_layout(cr).unwrap();
+    layout.set_font_description(font);
+    layout.set_text(&text.text);
+    layout.set_attributes(&text.formatting);
+    layout
+}
+
+/// A section of an index-style listing, such as the concordance.
+#[derive(Debug)]
+pub struct IndexSection {
+    pub heading: String,
+    pub entries: Vec<IndexEntry>,
+}
+
+/// A line of an index: an optional key (like a number in another book),
+/// some text, and the page it refers to.
+#[derive(Debug)]
+pub struct IndexEntry {
+    pub key: String,
+    pub text: String,
+    pub page: u32,
+}
+
+const INDEX_KEY_WIDTH: Points = 48.0;
+
+/// Print an index-style listing, starting new pages as needed.  Returns the
+/// number of pages.
+pub fn pdf_index(path: &FsPath, title: &str, sections: &[IndexSection])
+-> Result<u32, ()> {
+    let surface = cairo::PDFSurface::create(path, PAGE_WIDTH, PAGE_HEIGHT);
+    let cr = cairo::Context::new(&surface);
+    let left = points_from_inches(1.5);
+    let right = PAGE_WIDTH - MARGIN_RIGHT;
+    let top = points_from_inches(0.5);
+    let bottom = PAGE_HEIGHT - MARGIN_BOTTOM;
+
+    let layout = pc::create_layout(&cr).unwrap();
+    BASE_FONT.with(|font| layout.set_font_description(font));
+    let bold = pango::AttrList::new();
+    bold.insert(pango::Attribute::new_weight(pango::Weight::Bold).unwrap());
+    let plain = pango::AttrList::new();
+
+    let mut heading = FormattedText::new();
+    heading.text = title.to_string();
+    cr.move_to(left, top);
+    let mut y = top + TITLE_FONT.with(|font| draw_text(&cr, font, &heading))
+        .height() * 1.5;
+    let mut pages = 1;
+
+    let line = |text: &str, attrs: &pango::AttrList, x: Points, y: Points,
+                mode: Mode| {
+        layout.set_text(text);
+        layout.set_attributes(attrs);
+        cr.move_to(x, y);
+        show_layout(&cr, mode, &layout);
+        let size: Size = layout.get_size().into();
+        size.map(points_from_pango)
+    };
+
+    for section in sections {
+        // Keep a heading with at least the first of its entries.
+        let height = line(&section.heading, &bold, left, y, Mode::Measure).height();
+        if y + 2.0 * height > bottom {
+            cr.show_page();
+            pages += 1;
+            y = top;
+        }
+        line(&section.heading, &bold, left, y, Mode::Paint);
+        y += height;
+        for entry in &section.entries {
+            if y + height > bottom {
+                cr.show_page();
+                pages += 1;
+                y = top;
+            }
+            line(&entry.key, &plain, left, y, Mode::Paint);
+            line(&entry.text, &plain, left + INDEX_KEY_WIDTH, y, Mode::Paint);
+            let page = entry.page.to_string();
+            let width = line(&page, &plain, 0.0, 0.0, Mode::Measure).width();
+            line(&page, &plain, right - width, y, Mode::Paint);
+            y += height;
+        }
+        y += height / 2.0;
+    }
+    cr.show_page();
+    Ok(pages)
+}
+
+/// How a song's verses were fitted onto the page.
+#[derive(Clone, Copy, Debug)]
+pub struct Fit {
+    pub font_size: Points,
+    pub columns: u32,
+    /// The verses don't fit even at the minimum size, so they run off the
+    /// bottom or right of the page.
+    pub overflow: bool,
+    /// How many lines were wrapped rather than shrinking the song.
+    pub wrapped_lines: u32,
+    /// A ⟦parallel⟧ song overflowed, so it was printed in one column
+    /// instead of side by side.
+    pub parallel_dropped: bool,
 }
 
 #[derive(Clone, Debug)]
@@ -159,170 +606,865 @@ struct LayoutConfig<'a> {
     song: &'a Song,
     font_size: Points,
     verse_gap: Points,
-    column_break: Option<Points>,
+    wrap: Option<Wrap>,
+}
+
+/// How to wrap lines too wide for their column.
+#[derive(Clone, Copy, Debug)]
+struct Wrap {
+    width: Points,
+    /// Where continuation lines start, relative to the start of the verse.
+    /// This is deeper than any indent level in the song, so a wrapped line
+    /// can't be mistaken for an indented one.
+    indent: Points,
+}
+
+impl Wrap {
+    fn new(song: &Song, width: Points) -> Wrap {
+        let deepest = song.verses.iter()
+            .filter_map(verse_lines)
+            .flat_map(|lines| lines.iter().map(|line| line.indent))
+            .max()
+            .unwrap_or(0);
+        Wrap { width, indent: f64::from(deepest + 2) * INDENT }
+    }
 }
 
 impl<'a> LayoutConfig<'a> {
-    fn new(song: &'a Song) -> LayoutConfig<'a> {
+    fn new(song: &'a Song, font_size: Points) -> LayoutConfig<'a> {
         LayoutConfig {
             song,
-            font_size: FONT_SIZE.into(),
+            font_size,
             verse_gap: 14.0,
-            column_break: None,
+            wrap: None,
         }
     }
 
-    fn shrink_h(&mut self) -> Result<(), ()> {
-        if self.font_size > MIN_FONT_SIZE.into() {
-            self.font_size -= 0.5;
-            Ok(())
-        } else {
-            Err(())
+    fn pen(&self, mode: Mode) -> Pen {
+        let mut font = BASE_FONT.with(FontDescription::clone);
+        font.set_absolute_size(pango_from_points(self.font_size));
+        Pen {
+            font,
+            mode,
+            wrap: self.wrap,
+            wrapped: Cell::new(0),
+            lang: RefCell::new(self.song.lang().map(String::from)),
+            rtl: Cell::new(false),
+            mirror: Cell::new(None),
         }
     }
 
-    fn shrink_v(&mut self) -> Result<(), ()> {
-        if self.font_size > MIN_FONT_SIZE.into() {
-            self.font_size -= 0.5;
-            Ok(())
-        } else {
-            Err(())
+    /// The space taken by each verse, including the gap above it, and the
+    /// number of lines that had to be wrapped.
+    fn measure_verses(&self, cr: &Cr) -> (Vec<Size>, u32) {
+        let pen = self.pen(Mode::Measure);
+        let langs = self.song.verse_langs();
+        let sizes = self.song.verses.iter().zip(langs).map(|(verse, lang)| {
+            pen.set_lang(lang);
+            pen.rtl.set(is_rtl(lang, verse));
+            let Size(w, h) = draw_verse(cr, &pen, verse);
+            Size(w, h + self.gap_before(verse))
+        }).collect();
+        (sizes, pen.wrapped.get())
+    }
+
+    fn gap_before(&self, verse: &Verse) -> Points {
+        match *verse {
+            Verse::RefrainDef(_, _) => 0.0,
+            _ => self.verse_gap,
+        }
+    }
+}
+
+/// Whether a drawing function should paint, or only work out how much space
+/// it would take.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    Paint,
+    Measure,
+}
+
+fn show_layout(cr: &Cr, mode: Mode, layout: &pango::Layout) {
+    if mode == Mode::Paint {
+        pc::show_layout(cr, layout);
+    }
+}
+
+/// Everything the verse-drawing functions need besides the cairo context.
+struct Pen {
+    font: FontDescription,
+    mode: Mode,
+    wrap: Option<Wrap>,
+    /// A count of lines that have needed wrapping.
+    wrapped: Cell<u32>,
+    /// The language of the text being drawn, for hyphenation and choosing
+    /// fallback fonts.
+    lang: RefCell<Option<String>>,
+    /// Whether the verse being drawn reads right to left.
+    rtl: Cell<bool>,
+    /// When painting a right-to-left verse, the left and right edges of its
+    /// column.  Everything is drawn mirrored between them, so that indents,
+    /// verse numbers and whatever hangs off the end of a line go the other
+    /// way.
+    mirror: Cell<Option<(Points, Points)>>,
+}
+
+impl Pen {
+    fn set_lang(&self, lang: Option<&str>) {
+        *self.lang.borrow_mut() = lang.map(String::from);
+    }
+
+    /// A layout in the pen's font and language.
+    fn layout(&self, cr: &Cr) -> pango::Layout {
+        let layout = pc::create_layout(cr).unwrap();
+        layout.set_font_description(&self.font);
+        if let Some(ref lang) = *self.lang.borrow() {
+            // Each pangocairo layout has a context of its own.
+            let context = layout.get_context().unwrap();
+            context.set_language(&pango::Language::from_string(lang));
+            layout.context_changed();
+        }
+        if self.rtl.get() {
+            set_rtl(&layout);
+        }
+        layout
+    }
+
+    /// Where something `width` wide that would go at `x` is drawn: the same
+    /// place, or its mirror image in a right-to-left verse.
+    fn x(&self, x: Points, width: Points) -> Points {
+        match self.mirror.get() {
+            Some((left, right)) => left + right - x - width,
+            None => x,
+        }
+    }
+
+    /// Show a layout at the current point, mirrored if need be.
+    fn show(&self, cr: &Cr, layout: &pango::Layout) {
+        if self.mode != Mode::Paint {
+            return;
         }
+        let width = match layout.get_width() {
+            -1 => layout.get_size().0,
+            width => width,
+        };
+        let (x, y) = cr.get_current_point();
+        cr.move_to(self.x(x, points_from_pango(width)), y);
+        pc::show_layout(cr, layout);
+        cr.move_to(x, y);
     }
 }
 
-fn try_draw_verses(cr: &Cr, song: &Song) -> Result<(), ()> {
+/// Lay out text right to left, against the right edge of the layout's
+/// width.  Otherwise a line starting with a Latin name would run left to
+/// right.
+fn set_rtl(layout: &pango::Layout) {
+    let context = layout.get_context().unwrap();
+    context.set_base_dir(pango::Direction::Rtl);
+    layout.set_auto_dir(false);
+    layout.set_alignment(pango::Alignment::Right);
+    layout.context_changed();
+}
+
+/// Whether the song as a whole reads right to left, for its title and
+/// footnotes: because of its language or, if it has none, its title.
+fn song_is_rtl(song: &Song) -> bool {
+    match song.lang() {
+        Some(lang) => rtl_language(lang),
+        None => song.title().is_some_and(|title| {
+            pango::find_base_dir(&title.text) == pango::Direction::Rtl
+        }),
+    }
+}
+
+/// Whether a verse reads right to left: because of its language or, if it
+/// has none, because of the first letter in it with a direction.
+fn is_rtl(lang: Option<&str>, verse: &Verse) -> bool {
+    if let Some(lang) = lang {
+        return rtl_language(lang);
+    }
+    let text = match *verse {
+        Verse::ChorusRef(ref label) | Verse::SectionBreak(ref label, _)
+        | Verse::SectionRef(_, ref label) => label.clone(),
+        _ => verse_lines(verse).into_iter().flatten()
+                               .map(|line| line.text.as_str())
+                               .collect::<Vec<_>>().join("\n"),
+    };
+    pango::find_base_dir(&text) == pango::Direction::Rtl
+}
+
+/// Searches for the largest font size at which the verses fit in the space
+/// available, preferring fewer columns at any given size.
+struct Fitter<'a> {
+    cr: &'a Cr,
+    song: &'a Song,
+    avail: Size,
+    step: Points,
+    width_strategy: WidthStrategy,
+}
+
+/// A way of arranging the verses that fits.
+struct Placement {
+    columns: u32,
+    wrap: Option<Wrap>,
+    wrapped_lines: u32,
+    /// The column each verse goes in.
+    column_of: Vec<u32>,
+    /// For ⟦parallel⟧ songs, where each verse starts below the top of its
+    /// column, so that the verses line up across the columns.
+    tops: Option<Vec<Points>>,
+}
+
+impl<'a> Fitter<'a> {
+    fn font_size(&self, step: i32) -> Points {
+        f64::from(MIN_FONT_SIZE) + f64::from(step) * self.step
+    }
+
+    /// The fewest columns the verses fit in at this size, if any.  With
+    /// `WidthStrategy::Wrap`, wrapping a few lines is preferred to adding a
+    /// column.
+    fn placement_at(&self, font_size: Points) -> Option<Placement> {
+        if let Some(split) = self.song.parallel_split() {
+            return self.parallel_placement_at(font_size, split);
+        }
+        let mut config = LayoutConfig::new(self.song, font_size);
+        let (sizes, _) = config.measure_verses(self.cr);
+        for columns in 1..MAX_COLUMNS + 1 {
+            let width = column_width(self.avail.width(), columns,
+                                     column_gap(self.song));
+            if let Some(column_of) = self.pack(&sizes, width, columns) {
+                return Some(Placement {
+                    columns, wrap: None, wrapped_lines: 0, column_of, tops: None,
+                });
+            }
+            if self.width_strategy != WidthStrategy::Wrap {
+                continue;
+            }
+            config.wrap = Some(Wrap::new(self.song, width));
+            let (wrapped_sizes, wrapped_lines) = config.measure_verses(self.cr);
+            if wrapped_lines > MAX_WRAPPED_LINES {
+                continue;
+            }
+            if let Some(column_of) = self.pack(&wrapped_sizes, width, columns) {
+                return Some(Placement {
+                    columns, wrap: config.wrap, wrapped_lines, column_of, tops: None,
+                });
+            }
+        }
+        None
+    }
+
+    /// Two columns, split at the section break, with each row of verses
+    /// as tall as the taller of the two.
+    fn parallel_placement_at(&self, font_size: Points, split: usize)
+    -> Option<Placement> {
+        let mut config = LayoutConfig::new(self.song, font_size);
+        let width = column_width(self.avail.width(), 2, column_gap(self.song));
+        let (sizes, _) = config.measure_verses(self.cr);
+        if let Some((column_of, tops)) = self.pack_parallel(&sizes, width, split) {
+            return Some(Placement {
+                columns: 2, wrap: None, wrapped_lines: 0, column_of, tops: Some(tops),
+            });
+        }
+        if self.width_strategy != WidthStrategy::Wrap {
+            return None;
+        }
+        config.wrap = Some(Wrap::new(self.song, width));
+        let (wrapped_sizes, wrapped_lines) = config.measure_verses(self.cr);
+        if wrapped_lines > MAX_WRAPPED_LINES {
+            return None;
+        }
+        let (column_of, tops) = self.pack_parallel(&wrapped_sizes, width, split)?;
+        Some(Placement {
+            columns: 2, wrap: config.wrap, wrapped_lines, column_of, tops: Some(tops),
+        })
+    }
+
+    fn pack_parallel(&self, sizes: &[Size], width: Points, split: usize)
+    -> Option<(Vec<u32>, Vec<Points>)> {
+        if sizes.iter().any(|size| size.width() > width) {
+            return None;
+        }
+        let mut column_of = vec![0; sizes.len()];
+        let mut tops = vec![0.0; sizes.len()];
+        // The section break itself isn't drawn.
+        column_of[split] = 1;
+        let (left, right) = (split, sizes.len() - split - 1);
+        let mut y = 0.0;
+        for row in 0..left.max(right) {
+            let mut height = Maximum::new(0.0);
+            if row < left {
+                tops[row] = y;
+                height.see(sizes[row].height());
+            }
+            if row < right {
+                let i = split + 1 + row;
+                column_of[i] = 1;
+                tops[i] = y;
+                height.see(sizes[i].height());
+            }
+            y += height.get();
+        }
+        if y > self.avail.height() {
+            return None;
+        }
+        Some((column_of, tops))
+    }
+
+    fn pack(&self, sizes: &[Size], width: Points, columns: u32)
+    -> Option<Vec<u32>> {
+        if sizes.iter().any(|size| size.width() > width) {
+            return None;
+        }
+        pack_columns(sizes, columns, self.avail.height())
+    }
+
+    /// The largest size the verses fit at.  They fit at least as well at
+    /// any smaller size, so bisect.
+    fn fit(&self) -> (Fit, Placement) {
+        let max_step = (f64::from(FONT_SIZE - MIN_FONT_SIZE) / self.step) as i32;
+
+        // Binary search: `lo` always fits, `hi` never does.
+        let (mut lo, mut hi) = (0, max_step + 1);
+        if let Some(mut best) = self.placement_at(self.font_size(lo)) {
+            while hi - lo > 1 {
+                let mid = (lo + hi) / 2;
+                match self.placement_at(self.font_size(mid)) {
+                    Some(found) => {
+                        lo = mid;
+                        best = found;
+                    },
+                    None => hi = mid,
+                }
+            }
+            let fit = Fit {
+                font_size: self.font_size(lo),
+                columns: best.columns,
+                overflow: false,
+                wrapped_lines: best.wrapped_lines,
+                parallel_dropped: false,
+            };
+            return (fit, best);
+        }
+        let overflow = Fit {
+            font_size: self.font_size(0),
+            columns: 1,
+            overflow: true,
+            wrapped_lines: 0,
+            parallel_dropped: self.song.parallel_split().is_some(),
+        };
+        let placement = Placement {
+            columns: 1,
+            wrap: None,
+            wrapped_lines: 0,
+            column_of: vec![0; self.song.verses.len()],
+            tops: None,
+        };
+        (overflow, placement)
+    }
+}
+
+fn column_width(avail_width: Points, columns: u32, gap: Points) -> Points {
+    let columns = f64::from(columns);
+    (avail_width - (columns - 1.0) * gap) / columns
+}
+
+const VERSE_NUMBER_WIDTH: Points = 24.0;
+
+/// The space between columns, which must also fit verse numbers hanging to
+/// the left of the verses in later columns.
+fn column_gap(song: &Song) -> Points {
+    if song.numbered_verses() {
+        GUTTER + VERSE_NUMBER_WIDTH
+    } else {
+        GUTTER
+    }
+}
+
+/// Assign verses to columns in order, starting a new column whenever the
+/// next verse wouldn't fit.  Returns the column of each verse, or None if
+/// they need more than `columns` columns.
+fn pack_columns(sizes: &[Size], columns: u32, height: Points)
+-> Option<Vec<u32>> {
+    let mut placement = Vec::with_capacity(sizes.len());
+    let mut column = 0;
+    let mut used = 0.0;
+    for size in sizes {
+        if used > 0.0 && used + size.height() > height {
+            column += 1;
+            used = 0.0;
+        }
+        if column >= columns || size.height() > height {
+            return None;
+        }
+        used += size.height();
+        placement.push(column);
+    }
+    Some(placement)
+}
+
+/// Fit the verses between the current point and the page margins, leaving
+/// `reserved` points free at the bottom.
+fn fit_verses(cr: &Cr, song: &Song, options: &Options, reserved: Points)
+-> (Fit, Placement) {
     let (start_x, start_y) = cr.get_current_point();
-    let avail_width = PAGE_WIDTH - start_x - MARGIN_RIGHT;
-    let avail_height = PAGE_HEIGHT - start_y - points_from_inches(0.5);
-    let mut config = LayoutConfig::new(song);
+    let fitter = Fitter {
+        cr,
+        song,
+        avail: Size(PAGE_WIDTH - start_x - MARGIN_RIGHT,
+                    PAGE_HEIGHT - start_y - MARGIN_BOTTOM - reserved),
+        step: if options.fractional_sizes { FRACTIONAL_FONT_STEP } else { FONT_STEP },
+        width_strategy: options.width_strategy,
+    };
+    fitter.fit()
+}
 
-    loop {
-        cr.move_to(start_x, start_y);
-        let (pat, size) = draw_verses(cr, &config);
-        if size.width() > avail_width {
-            config.shrink_h()?;
-        } else if size.height() > avail_height {
-            config.shrink_v()?;
+thread_local! {
+    static FOOTNOTE_FONT: FontDescription = {
+        let mut font = BASE_FONT.with(FontDescription::clone);
+        font.set_absolute_size(pango_from_points(10.0));
+        font
+    };
+}
+
+const FOOTNOTE_RULE_GAP: Points = 14.0;
+const FOOTNOTE_MARKER_GAP: Points = 3.0;
+
+/// Draw the footnote area: a short rule, then each note with its marker
+/// hanging to the left, or on the right if the song reads right to left.
+/// Returns the height of the whole area.
+fn draw_footnotes(cr: &Cr, footnotes: &[&Footnote], width: Points, rtl: bool,
+                  mode: Mode) -> Points {
+    if footnotes.is_empty() {
+        return 0.0;
+    }
+    let (x, y) = cr.get_current_point();
+    if mode == Mode::Paint {
+        cr.set_line_width(0.5);
+        let rule_x = if rtl { x + width * 3.0 / 4.0 } else { x };
+        cr.move_to(rule_x, y + FOOTNOTE_RULE_GAP / 2.0);
+        cr.rel_line_to(width / 4.0, 0.0);
+        cr.stroke();
+    }
+
+    let marker_layout = pc::create_layout(cr).unwrap();
+    let text_layout = pc::create_layout(cr).unwrap();
+    FOOTNOTE_FONT.with(|font| {
+        marker_layout.set_font_description(font);
+        text_layout.set_font_description(font);
+    });
+    text_layout.set_wrap(pango::WrapMode::Word);
+    if rtl {
+        set_rtl(&marker_layout);
+        set_rtl(&text_layout);
+    }
+
+    let mut height = FOOTNOTE_RULE_GAP;
+    for footnote in footnotes {
+        marker_layout.set_text(&footnote.marker);
+        let (marker_width, _) = marker_layout.get_size();
+        let text_x = points_from_pango(marker_width) + FOOTNOTE_MARKER_GAP;
+
+        text_layout.set_width(pango_from_points(width - text_x) as i32);
+        text_layout.set_text(&footnote.text.text);
+        text_layout.set_attributes(&footnote.text.formatting);
+
+        if rtl {
+            cr.move_to(x + width - points_from_pango(marker_width), y + height);
+            show_layout(cr, mode, &marker_layout);
+            cr.move_to(x, y + height);
         } else {
-            cr.set_source(&*pat);
-            cr.paint();
-            return Ok(());
+            cr.move_to(x, y + height);
+            show_layout(cr, mode, &marker_layout);
+            cr.move_to(x + text_x, y + height);
         }
+        show_layout(cr, mode, &text_layout);
+
+        let (_, text_height) = text_layout.get_size();
+        height += points_from_pango(text_height);
     }
+    cr.move_to(x, y);
+    height
 }
 
-fn draw_verses(cr: &Cr, config: &LayoutConfig) -> (Box<cairo::Pattern>, Size) {
+fn draw_verses(cr: &Cr, config: &LayoutConfig, avail_width: Points,
+               placement: &Placement) {
     let (start_x, start_y) = cr.get_current_point();
-    let mut font = BASE_FONT.clone();
-    font.set_absolute_size(pango_from_points(config.font_size));
-    let mut max_width = Maximum::new(0.0);
-    let mut max_height = Maximum::new(0.0);
-    let mut height = 0.0;
-
-    cr.push_group();
-    for verse in &config.song.verses {
-        if let Verse::RefrainDef(_, _) = *verse {} else {
-            cr.rel_move_to(0.0, 14.0);
-        }
-        let Size(w, h) = draw_verse(&cr, &font, &verse);
-        max_width.see(w);
-        height += h + 14.0;
-        if let Some(h) = config.column_break {
-            if height > h {
-                max_height.see(height);
-                height = 0.0;
-                cr.move_to(start_x + max_width.get() + GUTTER, start_y);
+    let pen = config.pen(Mode::Paint);
+    let gap = column_gap(config.song);
+    let width = column_width(avail_width, placement.columns, gap);
+    let numbers = config.song.verse_numbers();
+    let langs = config.song.verse_langs();
+    let mut column = 0;
+
+    for (i, ((verse, &verse_column), number)) in config.song.verses.iter()
+                                                     .zip(&placement.column_of)
+                                                     .zip(numbers)
+                                                     .enumerate() {
+        let column_x = start_x + f64::from(verse_column) * (width + gap);
+        if let Some(ref tops) = placement.tops {
+            if let Verse::SectionBreak(_, _) = *verse {
+                continue;
             }
+            cr.move_to(column_x, start_y + tops[i]);
+        } else if verse_column != column {
+            column = verse_column;
+            cr.move_to(column_x, start_y);
         }
+        pen.set_lang(langs[i]);
+        let rtl = is_rtl(langs[i], verse);
+        pen.rtl.set(rtl);
+        pen.mirror.set(if rtl { Some((column_x, column_x + width)) } else { None });
+        cr.rel_move_to(0.0, config.gap_before(verse));
+        if let Some(number) = number {
+            draw_verse_number(cr, &pen, number);
+        }
+        draw_verse(cr, &pen, verse);
     }
-    let pat = cr.pop_group();
-    max_height.see(height);
-    (pat, Size(max_width.get(), max_height.get()))
 }
 
-fn draw_verse(cr: &Cr, font: &FontDescription, verse: &Verse) -> Size {
+/// Draw "1." and so on in the gutter to the left of the current point.
+fn draw_verse_number(cr: &Cr, pen: &Pen, number: u32) {
+    let layout = pen.layout(cr);
+    layout.set_text(&format!("{}.", number));
+    let (width, _) = layout.get_size();
+    let shift = points_from_pango(width) + VERSE_NUMBER_WIDTH / 4.0;
+    cr.rel_move_to(-shift, 0.0);
+    pen.show(cr, &layout);
+    cr.rel_move_to(shift, 0.0);
+}
+
+fn draw_verse(cr: &Cr, pen: &Pen, verse: &Verse) -> Size {
     match *verse {
         Verse::Normal(ref lines) => {
-            draw_lines(cr, &font, lines)
+            draw_lines(cr, pen, lines, 0.0)
         },
         Verse::ChorusDef(ref label, ref lines) => {
             let label = &format!("{}:", label);
-            let Size(label_w, label_h) = draw_label(cr, &font, label);
+            let Size(label_w, label_h) = draw_label(cr, pen, label);
             cr.rel_move_to(0.0, label_h);
-            let Size(body_w, body_h) = draw_lines(cr, &font, lines);
+            let Size(body_w, body_h) = draw_lines(cr, pen, lines, 0.0);
             Size(label_w.max(body_w), label_h + body_h)
         },
         Verse::RefrainDef(ref label, ref lines) => {
             let label = &format!("{}: ", label);
-            let Size(label_w, _) = draw_label(cr, &font, label);
+            let Size(label_w, _) = draw_label(cr, pen, label);
             cr.rel_move_to(label_w, 0.0);
-            let Size(body_w, body_h) = draw_lines(cr, &font, lines);
+            let Size(body_w, body_h) = draw_lines(cr, pen, lines, label_w);
             cr.rel_move_to(-label_w, 0.0);
             Size(label_w + body_w, body_h)
         },
         Verse::ChorusRef(ref label) => {
-            draw_marker(cr, &font, label)
+            draw_marker(cr, pen, label)
+        },
+        Verse::SectionBreak(ref label, _) => {
+            draw_marker(cr, pen, label)
+        },
+        Verse::SectionDef(_, ref label, ref lines) => {
+            let Size(label_w, label_h) = draw_section_label(cr, pen, label);
+            cr.rel_move_to(INDENT, label_h);
+            let Size(body_w, body_h) = draw_lines(cr, pen, lines, INDENT);
+            cr.rel_move_to(-INDENT, 0.0);
+            Size(label_w.max(INDENT + body_w), label_h + body_h)
         },
-        Verse::SectionBreak(ref label) => {
-            draw_marker(cr, &font, label)
+        Verse::SectionRef(_, ref label) => {
+            draw_marker(cr, pen, label)
         },
     }
 }
 
-fn draw_lines(cr: &Cr, font: &FontDescription, lines: &[FormattedText])
+/// Draw lines of a verse whose body starts `offset` from the left of the
+/// column.
+fn draw_lines(cr: &Cr, pen: &Pen, lines: &[FormattedText], offset: Points)
 -> Size {
-    let layout = pc::create_layout(&cr).unwrap();
-    layout.set_font_description(font);
+    let layout = pen.layout(cr);
+    if pen.wrap.is_some() {
+        layout.set_wrap(pango::WrapMode::Word);
+    }
     let mut max_width = Maximum::new(0.0);
     let mut total_height = 0.0;
+    // Where to put "×2" and so on, once we know how wide the verse is.
+    let mut multipliers = Vec::new();
 
     for line in lines {
         let indent = f64::from(line.indent) * INDENT;
         cr.rel_move_to(indent, 0.0);
 
-        layout.set_text(&line.text);
-        layout.set_attributes(&line.formatting);
-        pc::show_layout(cr, &layout);
+        if line.kind == LineKind::ChorusMarker {
+            // draw_marker moves down past the marker itself.
+            let Size(width, height) = draw_marker(cr, pen, &line.text);
+            cr.rel_move_to(-indent, 0.0);
+            max_width.see(indent + width);
+            total_height += height;
+            continue;
+        }
+
+        let mut text = line.text.clone();
+        let formatting = line.formatting.copy().unwrap();
+        if line.repeat_start {
+            text.insert_str(0, REPEAT_START);
+            formatting.splice(&pango::AttrList::new(), 0, REPEAT_START.len() as i32);
+        }
+        if line.repeat_end.is_some() {
+            text.push_str(REPEAT_END);
+        }
+        if line.kind == LineKind::InlineChorus {
+            formatting.insert_before(
+                pango::Attribute::new_style(pango::Style::Italic).unwrap());
+        }
+        layout.set_text(&text);
+        layout.set_attributes(&formatting);
+        if let Some(wrap) = pen.wrap {
+            layout.set_width(pango_from_points(wrap.width - offset - indent) as i32);
+            // A negative indent is a hanging indent in pango.
+            layout.set_indent(-pango_from_points(wrap.indent - indent) as i32);
+        }
+        pen.show(cr, &layout);
+        if layout.get_line_count() > 1 {
+            pen.wrapped.set(pen.wrapped.get() + 1);
+        }
 
         cr.rel_move_to(-indent, 0.0);
 
         let (line_width, line_height) = layout.get_size();
-        max_width.see(indent + points_from_pango(line_width));
+        if line.kind == LineKind::InlineChorus {
+            draw_chorus_bar(cr, pen, points_from_pango(line_height));
+        }
+        let mut line_width = points_from_pango(line_width);
+        if let Some(ref descant) = line.descant {
+            let x = indent + line_width + DESCANT_GAP;
+            cr.rel_move_to(x, 0.0);
+            line_width += DESCANT_GAP + draw_descant(cr, pen, descant).width();
+            cr.rel_move_to(-x, 0.0);
+        }
+        max_width.see(indent + line_width);
+
+        let mut multiplier = Vec::new();
+        if line.times > 1 {
+            multiplier.push(format!("×{}", line.times));
+        }
+        match line.repeat_end {
+            // The repeat signs alone mean twice.
+            Some(times) if times > 2 => multiplier.push(format!("×{}", times)),
+            _ => {},
+        }
+        if !multiplier.is_empty() {
+            multipliers.push((total_height, multiplier.join(" ")));
+        }
+
         cr.rel_move_to(0.0, points_from_pango(line_height));
         total_height += points_from_pango(line_height);
     }
+
+    let x = max_width.get() + MULTIPLIER_GAP;
+    for (y, multiplier) in multipliers {
+        cr.rel_move_to(x, y - total_height);
+        layout.set_text(&multiplier);
+        layout.set_attributes(&pango::AttrList::new());
+        if pen.wrap.is_some() {
+            layout.set_width(-1);
+            layout.set_indent(0);
+        }
+        pen.show(cr, &layout);
+        let (width, _) = layout.get_size();
+        max_width.see(x + points_from_pango(width));
+        cr.rel_move_to(-x, total_height - y);
+    }
     Size(max_width.get(), total_height)
 }
 
-fn draw_label(cr: &Cr, font: &FontDescription, label: &str) -> Size {
+const REPEAT_START: &str = "‖: ";
+const REPEAT_END: &str = " :‖";
+const MULTIPLIER_GAP: Points = 12.0;
+
+const DESCANT_GAP: Points = 12.0;
+const DESCANT_SCALE: f64 = 0.8;
+
+/// Draw descant words in brackets, smaller and in italic, at the current
+/// point.
+fn draw_descant(cr: &Cr, pen: &Pen, descant: &FormattedText) -> Size {
+    let mut font = pen.font.clone();
+    font.set_absolute_size(f64::from(pen.font.get_size()) * DESCANT_SCALE);
+    font.set_style(pango::Style::Italic);
+
+    // Splicing shifts the descant's own formatting past the "[".
+    let formatting = descant.formatting.copy().unwrap();
+    formatting.splice(&pango::AttrList::new(), 0, "[".len() as i32);
+
+    let layout = pen.layout(cr);
+    layout.set_font_description(&font);
+    layout.set_text(&format!("[{}]", descant.text));
+    layout.set_attributes(&formatting);
+    pen.show(cr, &layout);
+
+    let size: Size = layout.get_size().into();
+    size.map(points_from_pango)
+}
+
+const CHORUS_BAR_OFFSET: Points = 4.0;
+
+/// Mark an inline chorus line with a bar just before the verse: to its
+/// left, or its right if it reads right to left.  The bars of consecutive
+/// chorus lines join up.
+fn draw_chorus_bar(cr: &Cr, pen: &Pen, height: Points) {
+    if pen.mode != Mode::Paint {
+        return;
+    }
+    let (x, y) = cr.get_current_point();
+    cr.set_line_width(1.0);
+    cr.move_to(pen.x(x - CHORUS_BAR_OFFSET, 0.0), y);
+    cr.rel_line_to(0.0, height);
+    cr.stroke();
+    cr.move_to(x, y);
+}
+
+fn draw_label(cr: &Cr, pen: &Pen, label: &str) -> Size {
     let bold = pango::AttrList::new();
     bold.insert(pango::Attribute::new_weight(pango::Weight::Bold).unwrap());
 
-    let layout = pc::create_layout(&cr).unwrap();
-    layout.set_font_description(font);
+    let layout = pen.layout(cr);
 
     layout.set_text(label);
     layout.set_attributes(&bold);
-    pc::show_layout(cr, &layout);
+    pen.show(cr, &layout);
+
+    let size: Size = layout.get_size().into();
+    size.map(points_from_pango)
+}
+
+/// Label a bridge, coda and so on: in small capitals, so it isn't mistaken
+/// for a chorus, with the section's lines indented under it.
+fn draw_section_label(cr: &Cr, pen: &Pen, label: &str) -> Size {
+    let small_caps = pango::AttrList::new();
+    small_caps.insert(pango::Attribute::new_variant(pango::Variant::SmallCaps).unwrap());
+
+    let layout = pen.layout(cr);
+
+    layout.set_text(label);
+    layout.set_attributes(&small_caps);
+    pen.show(cr, &layout);
 
     let size: Size = layout.get_size().into();
     size.map(points_from_pango)
 }
 
-fn draw_marker(cr: &Cr, font: &FontDescription, label: &str) -> Size {
+fn draw_marker(cr: &Cr, pen: &Pen, label: &str) -> Size {
     let italic = pango::AttrList::new();
     italic.insert(pango::Attribute::new_style(pango::Style::Italic).unwrap());
 
-    let layout = pc::create_layout(&cr).unwrap();
-    layout.set_font_description(font);
+    let layout = pen.layout(cr);
 
     layout.set_text(label);
     layout.set_attributes(&italic);
-    pc::show_layout(cr, &layout);
+    pen.show(cr, &layout);
 
     let size_: Size = layout.get_size().into();
     let size = size_.map(points_from_pango);
     cr.rel_move_to(0.0, size.height());
     size
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout() -> pango::Layout {
+        let surface = cairo::ImageSurface::create(cairo::Format::ARgb32, 100, 100).unwrap();
+        let cr = cairo::Context::new(&surface);
+        pc::create_layout(&cr).unwrap()
+    }
+
+    #[test]
+    fn an_overflowing_song_keeps_the_last_pdf() {
+        let dir = ::std::env::temp_dir()
+            .join(format!("songset-test-overflow-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("long.song");
+        fs::write(&path, "old").unwrap();
+        let options = Options::default();
+
+        let verses = vec!["Line\nLine\nLine\nLine"; 200].join("\n\n");
+        let long = ::parse_song(&format!("⟦title Long⟧\n\n{}", verses), 0).unwrap();
+        assert!(pdf_song(&path, &long, &options).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old");
+        assert!(!dir.join("long.song.partial").exists());
+
+        let short = ::parse_song("⟦title Short⟧\n\nLine", 0).unwrap();
+        let output = pdf_song(&path, &short, &options).unwrap();
+        assert!(!output.fit.overflow);
+        assert_eq!(output.fit.font_size, f64::from(FONT_SIZE));
+        assert!(fs::read(&path).unwrap().starts_with(b"%PDF"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn first_letters() {
+        let layout = layout();
+        assert_eq!(first_letter(&layout, "Amazing Grace"), "A");
+        assert_eq!(first_letter(&layout, "E\u{301}toile"), "E\u{301}");
+        assert_eq!(first_letter(&layout, "fine"), "f");
+        assert_eq!(first_letter(&layout, "क्षमा"), "क्ष");
+        assert_eq!(first_letter(&layout, "主祷文"), "主");
+        assert_eq!(first_letter(&layout, "שָׁלוֹם"), "שָׁ");
+        assert_eq!(first_letter(&layout, "سلام"), "س");
+        assert_eq!(first_letter(&layout, "A"), "A");
+    }
+
+    fn verse(text: &str) -> Verse {
+        let mut line = FormattedText::new();
+        line.text = text.to_string();
+        Verse::Normal(vec![line])
+    }
+
+    #[test]
+    fn rtl_verses() {
+        assert!(is_rtl(Some("he"), &verse("Shalom")));
+        assert!(is_rtl(Some("ar-EG"), &verse("سلام")));
+        assert!(!is_rtl(Some("en"), &verse("שָׁלוֹם")));
+        assert!(is_rtl(None, &verse("שָׁלוֹם עֲלֵיכֶם")));
+        assert!(is_rtl(None, &verse("123 سلام")));
+        assert!(!is_rtl(None, &verse("क्षमा")));
+        assert!(!is_rtl(None, &verse("主祷文")));
+        assert!(is_rtl(None, &Verse::ChorusRef("פזמון".to_string())));
+    }
+
+    /// The leftmost and rightmost columns with any ink in them.
+    fn ink_columns(surface: &mut cairo::ImageSurface) -> Option<(usize, usize)> {
+        let width = surface.get_width() as usize;
+        let stride = surface.get_stride() as usize;
+        let data = surface.get_data().unwrap();
+        let inked: Vec<usize> = (0..width).filter(|&x| {
+            data.chunks(stride).any(|row| row[x * 4..x * 4 + 4] != [0, 0, 0, 0])
+        }).collect();
+        Some((*inked.first()?, *inked.last()?))
+    }
+
+    #[test]
+    fn pen_mirrors_what_it_shows() {
+        for &(mirror, left_half) in &[(None, true), (Some((0.0, 200.0)), false)] {
+            let mut surface = cairo::ImageSurface::create(
+                cairo::Format::ARgb32, 200, 40).unwrap();
+            {
+                let cr = cairo::Context::new(&surface);
+                let pen = Pen {
+                    font: BASE_FONT.with(FontDescription::clone),
+                    mode: Mode::Paint,
+                    wrap: None,
+                    wrapped: Cell::new(0),
+                    lang: RefCell::new(None),
+                    rtl: Cell::new(false),
+                    mirror: Cell::new(mirror),
+                };
+                let layout = pen.layout(&cr);
+                layout.set_text("Amen");
+                cr.move_to(10.0, 0.0);
+                pen.show(&cr, &layout);
+                // The current point is left where it was.
+                assert_eq!(cr.get_current_point(), (10.0, 0.0));
+            }
+            let (first, last) = ink_columns(&mut surface).expect("Nothing drawn");
+            assert_eq!(last < 100, left_half, "{:?}: ink from {} to {}", mirror, first, last);
+            assert_eq!(first > 100, !left_half, "{:?}: ink from {} to {}", mirror, first, last);
+        }
+    }
+}